@@ -0,0 +1,664 @@
+use crate::report::get_clade_counts;
+use crate::taxonomy::Taxonomy;
+use crate::utils::open_file;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Result, Write};
+use std::path::Path;
+
+/// Genome size table written into the database directory by merge-fna
+///
+/// One `taxid<TAB>bases` line per taxon with a downloaded assembly, the bases
+/// being the median length of the taxon's assemblies (see [`median_genome_sizes`]).
+pub const GENOME_SIZES_FILENAME: &str = "genome_sizes.tsv";
+
+/// Assembly size table written into the database directory by merge-fna
+///
+/// One `taxid<TAB>assembly<TAB>bases` line per downloaded assembly, the bases
+/// being the summed length of its sequences.
+pub const ASSEMBLY_SIZES_FILENAME: &str = "assembly_sizes.tsv";
+
+/// Sample sheet column declaring the spike-in controls of a sample
+pub const SPIKE_IN_COLUMN: &str = "spike_in";
+
+/// Denominator of the relative abundances
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Normalization {
+    /// Fraction of the classified reads
+    Classified,
+    /// Fraction of all reads, unclassified ones included
+    Total,
+    /// Reads per base of the genome, scaled to sum to 1 over taxa with a known genome size
+    GenomeSize,
+}
+
+impl Normalization {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Normalization::Classified => "classified",
+            Normalization::Total => "total",
+            Normalization::GenomeSize => "genome-size",
+        }
+    }
+}
+
+/// Reads a genome size table (`taxid<TAB>bases`), keyed by external taxid
+pub fn read_genome_sizes<P: AsRef<Path>>(filename: P) -> Result<HashMap<u64, u64>> {
+    let reader = BufReader::new(open_file(filename)?);
+    let mut sizes = HashMap::new();
+    for line in reader.lines() {
+        let line = line?;
+        if let Some((taxid, bases)) = line.trim().split_once('\t') {
+            if let (Ok(taxid), Ok(bases)) = (taxid.parse::<u64>(), bases.parse::<u64>()) {
+                sizes.insert(taxid, bases);
+            }
+        }
+    }
+    Ok(sizes)
+}
+
+/// Writes a genome size table, sorted by taxid
+pub fn write_genome_sizes<P: AsRef<Path>>(filename: P, sizes: &HashMap<u64, u64>) -> Result<()> {
+    let mut sizes: Vec<(&u64, &u64)> = sizes.iter().collect();
+    sizes.sort_unstable();
+    let mut writer = BufWriter::new(File::create(filename)?);
+    for (taxid, bases) in sizes {
+        writeln!(writer, "{}\t{}", taxid, bases)?;
+    }
+    writer.flush()
+}
+
+/// Writes an assembly size table, sorted by taxid and assembly
+pub fn write_assembly_sizes<P: AsRef<Path>>(
+    filename: P,
+    assemblies: &[(u64, String, u64)],
+) -> Result<()> {
+    let mut assemblies: Vec<&(u64, String, u64)> = assemblies.iter().collect();
+    assemblies.sort_unstable();
+    let mut writer = BufWriter::new(File::create(filename)?);
+    for (taxid, assembly, bases) in assemblies {
+        writeln!(writer, "{}\t{}\t{}", taxid, assembly, bases)?;
+    }
+    writer.flush()
+}
+
+/// Genome size of each taxon from the sizes of its assemblies
+///
+/// A taxon with several assemblies (e.g. many genomes of one species) takes
+/// the median assembly size, so the size does not grow with the number of
+/// assemblies in the library.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::abundance::median_genome_sizes;
+///
+/// let assemblies = vec![
+///     (562, "a".to_string(), 5_000_000),
+///     (562, "b".to_string(), 4_600_000),
+///     (562, "c".to_string(), 5_500_000),
+///     (1280, "d".to_string(), 2_800_000),
+///     (1280, "e".to_string(), 2_900_000),
+/// ];
+/// let sizes = median_genome_sizes(&assemblies);
+/// assert_eq!(sizes[&562], 5_000_000);
+/// assert_eq!(sizes[&1280], 2_850_000);
+/// ```
+pub fn median_genome_sizes(assemblies: &[(u64, String, u64)]) -> HashMap<u64, u64> {
+    let mut by_taxon: HashMap<u64, Vec<u64>> = HashMap::new();
+    for (taxid, _, bases) in assemblies {
+        by_taxon.entry(*taxid).or_default().push(*bases);
+    }
+    by_taxon
+        .into_iter()
+        .map(|(taxid, mut sizes)| {
+            sizes.sort_unstable();
+            let mid = sizes.len() / 2;
+            let median = if sizes.len() % 2 == 0 {
+                (sizes[mid - 1] + sizes[mid]) / 2
+            } else {
+                sizes[mid]
+            };
+            (taxid, median)
+        })
+        .collect()
+}
+
+/// Estimated genome sizes of the taxa at `rank`, keyed by internal id
+///
+/// A taxon at the rank takes the mean size of all taxa in its subtree (itself
+/// included) that have a size, e.g. the strains of a species.
+pub fn rank_genome_sizes(
+    taxonomy: &Taxonomy,
+    sizes: &HashMap<u64, u64>,
+    rank: &str,
+) -> HashMap<u32, u64> {
+    let mut totals: HashMap<u32, (u64, u64)> = HashMap::new();
+    for (&external_id, &bases) in sizes {
+        let mut taxid = taxonomy.get_internal_id(external_id);
+        while taxid != 0 {
+            if taxonomy.rank_of(taxid) == rank {
+                let total = totals.entry(taxid).or_insert((0, 0));
+                total.0 += bases;
+                total.1 += 1;
+                break;
+            }
+            taxid = taxonomy.nodes[taxid as usize].parent_id as u32;
+        }
+    }
+    totals
+        .into_iter()
+        .map(|(taxid, (bases, n))| (taxid, bases / n))
+        .collect()
+}
+
+/// Relative abundances of taxa given as (reads, genome size)
+///
+/// With genome size normalization taxa without a size get `None` and are left
+/// out of the denominator.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::abundance::{relative_abundances, Normalization};
+///
+/// let taxa = [(60, Some(2_000_000)), (30, Some(4_000_000)), (10, None)];
+/// assert_eq!(
+///     relative_abundances(&taxa, Normalization::Classified, 120, 200),
+///     vec![Some(0.5), Some(0.25), Some(1.0 / 12.0)]
+/// );
+/// assert_eq!(
+///     relative_abundances(&taxa, Normalization::Total, 120, 200),
+///     vec![Some(0.3), Some(0.15), Some(0.05)]
+/// );
+/// // 30 reads per Mbp against 7.5 reads per Mbp
+/// let percents: Vec<Option<f64>> = relative_abundances(&taxa, Normalization::GenomeSize, 120, 200)
+///     .into_iter()
+///     .map(|abundance| abundance.map(|a| (a * 100.0).round()))
+///     .collect();
+/// assert_eq!(percents, vec![Some(80.0), Some(20.0), None]);
+/// ```
+pub fn relative_abundances(
+    taxa: &[(u64, Option<u64>)],
+    normalization: Normalization,
+    classified: u64,
+    total: u64,
+) -> Vec<Option<f64>> {
+    let fraction = |reads: u64, denominator: u64| {
+        Some(if denominator == 0 {
+            0.0
+        } else {
+            reads as f64 / denominator as f64
+        })
+    };
+    match normalization {
+        Normalization::Classified => taxa
+            .iter()
+            .map(|&(reads, _)| fraction(reads, classified))
+            .collect(),
+        Normalization::Total => taxa
+            .iter()
+            .map(|&(reads, _)| fraction(reads, total))
+            .collect(),
+        Normalization::GenomeSize => {
+            let densities: Vec<Option<f64>> = taxa
+                .iter()
+                .map(|&(reads, size)| {
+                    size.filter(|&size| size > 0)
+                        .map(|size| reads as f64 / size as f64)
+                })
+                .collect();
+            let sum: f64 = densities.iter().flatten().sum();
+            densities
+                .into_iter()
+                .map(|density| density.map(|d| if sum > 0.0 { d / sum } else { 0.0 }))
+                .collect()
+        }
+    }
+}
+
+/// Parses the spike-in controls of a sample, `taxid:quantity` pairs separated by commas
+///
+/// The quantity is the amount added to the sample in any unit (cells, genome
+/// copies, ng), absolute abundances are reported in the same unit.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::abundance::parse_spike_ins;
+///
+/// assert_eq!(
+///     parse_spike_ins("1613:2e6, 1352:1000").unwrap(),
+///     vec![(1613, 2_000_000.0), (1352, 1000.0)]
+/// );
+/// assert!(parse_spike_ins("").unwrap().is_empty());
+/// assert!(parse_spike_ins("1613").is_err());
+/// ```
+pub fn parse_spike_ins(value: &str) -> Result<Vec<(u64, f64)>> {
+    value
+        .split(',')
+        .map(|spike_in| spike_in.trim())
+        .filter(|spike_in| !spike_in.is_empty())
+        .map(|spike_in| {
+            spike_in
+                .split_once(':')
+                .and_then(|(taxid, quantity)| {
+                    let quantity = quantity.trim().parse::<f64>().ok()?;
+                    Some((taxid.trim().parse::<u64>().ok()?, quantity))
+                })
+                .filter(|(_, quantity)| *quantity > 0.0)
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("invalid spike-in {}, expected taxid:quantity", spike_in),
+                    )
+                })
+        })
+        .collect()
+}
+
+/// Absolute abundances of taxa given as (reads, genome size), scaled by the spike-in recovery
+///
+/// Each spike-in is given as ((reads, genome size), quantity added). The recovery is
+/// the yield of all spike-ins per unit of quantity, in reads, or in reads per base
+/// with genome size normalization. Returns `None` for all taxa when no spike-in
+/// was recovered, and for taxa without a genome size when normalizing by it.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::abundance::absolute_abundances;
+///
+/// let taxa = [(500, Some(4_000_000)), (40, Some(2_000_000)), (10, None)];
+/// // 100 reads for 1000 cells of a spike-in with a 2 Mbp genome
+/// let spike_ins = [((100, Some(2_000_000)), 1000.0)];
+/// assert_eq!(
+///     absolute_abundances(&taxa, &spike_ins, false),
+///     vec![Some(5000.0), Some(400.0), Some(100.0)]
+/// );
+/// assert_eq!(
+///     absolute_abundances(&taxa, &spike_ins, true),
+///     vec![Some(2500.0), Some(400.0), None]
+/// );
+/// assert_eq!(
+///     absolute_abundances(&taxa, &[((0, None), 1000.0)], false),
+///     vec![None, None, None]
+/// );
+/// ```
+pub fn absolute_abundances(
+    taxa: &[(u64, Option<u64>)],
+    spike_ins: &[((u64, Option<u64>), f64)],
+    by_genome_size: bool,
+) -> Vec<Option<f64>> {
+    let density = |reads: u64, size: Option<u64>| {
+        if by_genome_size {
+            size.filter(|&size| size > 0)
+                .map(|size| reads as f64 / size as f64)
+        } else {
+            Some(reads as f64)
+        }
+    };
+    let (yields, quantity) = spike_ins
+        .iter()
+        .filter_map(|&((reads, size), quantity)| Some((density(reads, size)?, quantity)))
+        .fold((0.0, 0.0), |acc, (d, q)| (acc.0 + d, acc.1 + q));
+    let recovery = if quantity > 0.0 {
+        yields / quantity
+    } else {
+        0.0
+    };
+
+    taxa.iter()
+        .map(|&(reads, size)| {
+            if recovery > 0.0 {
+                density(reads, size).map(|d| d / recovery)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// A taxon at the abundance rank with its reads and abundance in one sample
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaxonAbundance {
+    /// Internal id
+    pub taxid: u32,
+    pub reads: u64,
+    pub genome_size: Option<u64>,
+    /// Relative abundance, `None` for taxa without a genome size when normalizing by it
+    pub abundance: Option<f64>,
+}
+
+/// Settings of the per-rank abundance table
+pub struct AbundanceOptions {
+    pub rank: String,
+    pub normalization: Normalization,
+    /// Genome sizes of the taxa at `rank`, by internal id
+    pub genome_sizes: HashMap<u32, u64>,
+    /// Genome size table as read, by external taxid, for the spike-in taxa
+    pub sizes: HashMap<u64, u64>,
+}
+
+impl AbundanceOptions {
+    /// Relative abundances of the taxa at the rank, most reads first
+    ///
+    /// `clade_counts` are the clade reads of each taxon (internal id), see
+    /// [`get_clade_counts`]. Reads classified above the rank count as classified
+    /// but belong to no taxon. The clades of the `spike_ins` (external ids) are
+    /// not part of the sample: taxa inside them get no row, and their reads are
+    /// left out of the rows of their ancestors and of the denominator.
+    pub fn abundances(
+        &self,
+        taxonomy: &Taxonomy,
+        clade_counts: &HashMap<u64, u64>,
+        total_reads: u64,
+        unclassified_reads: u64,
+        spike_ins: &[u64],
+    ) -> Vec<TaxonAbundance> {
+        let mut spike_in_ids: Vec<u32> = spike_ins
+            .iter()
+            .map(|&external_id| taxonomy.get_internal_id(external_id))
+            .filter(|&taxid| taxid != 0)
+            .collect();
+        spike_in_ids.sort_unstable();
+        spike_in_ids.dedup();
+        let in_clade =
+            |clade: u32, taxid: u32| clade == taxid || taxonomy.is_a_ancestor_of_b(clade, taxid);
+        // 嵌套的 spike-in 只按最外层的 clade 计数一次
+        let outer: Vec<u32> = spike_in_ids
+            .iter()
+            .copied()
+            .filter(|&taxid| {
+                !spike_in_ids
+                    .iter()
+                    .any(|&other| other != taxid && in_clade(other, taxid))
+            })
+            .collect();
+        let clade_reads = |taxid: u32| clade_counts.get(&(taxid as u64)).copied().unwrap_or(0);
+        let spike_in_reads: u64 = outer.iter().map(|&taxid| clade_reads(taxid)).sum();
+        let total_reads = total_reads.saturating_sub(spike_in_reads);
+        let classified_reads = total_reads.saturating_sub(unclassified_reads);
+
+        let mut rows: Vec<(u32, u64)> = clade_counts
+            .iter()
+            .filter(|(&taxid, _)| taxonomy.rank_of(taxid as u32) == self.rank)
+            .filter(|(&taxid, _)| !outer.iter().any(|&clade| in_clade(clade, taxid as u32)))
+            .map(|(&taxid, &reads)| {
+                let taxid = taxid as u32;
+                // spike-in 位于 rank 之下时, 从所属 taxon 中减去它的 reads
+                let spike_in_reads: u64 = outer
+                    .iter()
+                    .filter(|&&clade| taxonomy.is_a_ancestor_of_b(taxid, clade))
+                    .map(|&clade| clade_reads(clade))
+                    .sum();
+                (taxid, reads.saturating_sub(spike_in_reads))
+            })
+            .filter(|&(_, reads)| reads > 0)
+            .collect();
+        rows.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        let taxa: Vec<(u64, Option<u64>)> = rows
+            .iter()
+            .map(|&(taxid, reads)| (reads, self.genome_sizes.get(&taxid).copied()))
+            .collect();
+        let abundances =
+            relative_abundances(&taxa, self.normalization, classified_reads, total_reads);
+        rows.into_iter()
+            .zip(taxa)
+            .zip(abundances)
+            .map(
+                |(((taxid, reads), (_, genome_size)), abundance)| TaxonAbundance {
+                    taxid,
+                    reads,
+                    genome_size,
+                    abundance,
+                },
+            )
+            .collect()
+    }
+
+    /// Writes the relative abundances of the taxa at the rank, most reads first
+    ///
+    /// `call_counts` are the reads assigned to each taxon (internal id). The
+    /// `metadata` of the sample is written as `# key: value` lines before the
+    /// rows, empty values are skipped. Its `spike_in` column (see
+    /// [`parse_spike_ins`]) adds an absolute abundance column, see
+    /// [`absolute_abundances`], and leaves the spike-in clades out of the relative
+    /// abundances, see [`AbundanceOptions::abundances`].
+    pub fn write_table<P: AsRef<Path>>(
+        &self,
+        filename: P,
+        taxonomy: &Taxonomy,
+        call_counts: &HashMap<u64, u64>,
+        total_reads: u64,
+        unclassified_reads: u64,
+        metadata: &[(String, String)],
+    ) -> Result<()> {
+        let spike_ins = match metadata.iter().find(|(key, _)| key == SPIKE_IN_COLUMN) {
+            Some((_, value)) => parse_spike_ins(value)?,
+            None => Vec::new(),
+        };
+        let classified_reads = total_reads.saturating_sub(unclassified_reads);
+        let clade_counts = get_clade_counts(taxonomy, call_counts);
+        let spike_in_ids: Vec<u64> = spike_ins.iter().map(|&(taxid, _)| taxid).collect();
+        let rows = self.abundances(
+            taxonomy,
+            &clade_counts,
+            total_reads,
+            unclassified_reads,
+            &spike_in_ids,
+        );
+        let taxa: Vec<(u64, Option<u64>)> = rows
+            .iter()
+            .map(|row| (row.reads, row.genome_size))
+            .collect();
+
+        // spike-in 可以是任意 rank 的 taxon, 按自身的 clade 计数
+        let spike_in_taxa: Vec<((u64, Option<u64>), f64)> = spike_ins
+            .iter()
+            .map(|&(external_id, quantity)| {
+                let taxid = taxonomy.get_internal_id(external_id);
+                let reads = match taxid {
+                    0 => 0,
+                    _ => clade_counts.get(&(taxid as u64)).copied().unwrap_or(0),
+                };
+                let size = self
+                    .sizes
+                    .get(&external_id)
+                    .or_else(|| self.genome_sizes.get(&taxid))
+                    .copied();
+                ((reads, size), quantity)
+            })
+            .collect();
+        let by_genome_size = self.normalization == Normalization::GenomeSize;
+        let absolutes = absolute_abundances(&taxa, &spike_in_taxa, by_genome_size);
+
+        let mut writer = BufWriter::new(File::create(filename)?);
+        // spike-in 在下面逐个写出
+        for (key, value) in metadata
+            .iter()
+            .filter(|(key, value)| !value.is_empty() && key != SPIKE_IN_COLUMN)
+        {
+            writeln!(writer, "# {}: {}", key, value)?;
+        }
+        writeln!(writer, "# rank: {}", self.rank)?;
+        writeln!(writer, "# normalization: {}", self.normalization.as_str())?;
+        writeln!(writer, "# total_reads: {}", total_reads)?;
+        writeln!(writer, "# classified_reads: {}", classified_reads)?;
+        for ((external_id, quantity), ((reads, _), _)) in spike_ins.iter().zip(&spike_in_taxa) {
+            writeln!(
+                writer,
+                "# spike_in: {}\tquantity={}\treads={}",
+                external_id, quantity, reads
+            )?;
+        }
+        write!(writer, "taxid\tname\treads\tgenome_size\tabundance")?;
+        if !spike_ins.is_empty() {
+            write!(writer, "\tabsolute_abundance")?;
+        }
+        writeln!(writer)?;
+        let na = || "NA".to_string();
+        for (row, absolute) in rows.iter().zip(&absolutes) {
+            write!(
+                writer,
+                "{}\t{}\t{}\t{}\t{}",
+                taxonomy.nodes[row.taxid as usize].external_id,
+                taxonomy.name_of(row.taxid),
+                row.reads,
+                row.genome_size.map_or_else(na, |size| size.to_string()),
+                row.abundance
+                    .map_or_else(na, |abundance| format!("{:.6}", abundance)),
+            )?;
+            if !spike_ins.is_empty() {
+                let absolute = absolute.map_or_else(na, |absolute| format!("{:.2}", absolute));
+                write!(writer, "\t{}", absolute)?;
+            }
+            writeln!(writer)?;
+        }
+        writer.flush()
+    }
+}
+
+/// Relative abundances of several samples, written as one taxon x sample matrix
+///
+/// Each sample is a column headed by its label. The metadata of the samples
+/// follows the header as one `#key` row per metadata column, so the matrix can
+/// be joined with other tables without a separate sample sheet.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::abundance::{AbundanceMatrix, TaxonAbundance};
+///
+/// let taxon = |taxid: u32, abundance: f64| TaxonAbundance {
+///     taxid,
+///     reads: 10,
+///     genome_size: None,
+///     abundance: Some(abundance),
+/// };
+/// let mut matrix = AbundanceMatrix::default();
+/// let site = |value: &str| vec![("site".to_string(), value.to_string())];
+/// matrix.add_sample("1", site("gut"), &[taxon(5, 0.75), taxon(7, 0.25)]);
+/// matrix.add_sample("2", site("skin"), &[taxon(7, 1.0)]);
+///
+/// assert_eq!(matrix.metadata_keys(), vec!["site"]);
+/// // taxa with the highest summed abundance first, absent taxa have abundance 0
+/// assert_eq!(
+///     matrix.rows(),
+///     vec![(7, vec![Some(0.25), Some(1.0)]), (5, vec![Some(0.75), Some(0.0)])]
+/// );
+/// ```
+#[derive(Debug, Default)]
+pub struct AbundanceMatrix {
+    labels: Vec<String>,
+    metadata: Vec<Vec<(String, String)>>,
+    abundances: Vec<HashMap<u32, Option<f64>>>,
+}
+
+impl AbundanceMatrix {
+    /// Adds the abundances of one sample as a new column
+    pub fn add_sample(
+        &mut self,
+        label: &str,
+        metadata: Vec<(String, String)>,
+        abundances: &[TaxonAbundance],
+    ) {
+        self.labels.push(label.to_string());
+        self.metadata.push(metadata);
+        self.abundances.push(
+            abundances
+                .iter()
+                .map(|taxon| (taxon.taxid, taxon.abundance))
+                .collect(),
+        );
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// Metadata columns of all samples, in the order they first appear
+    pub fn metadata_keys(&self) -> Vec<&str> {
+        let mut keys: Vec<&str> = Vec::new();
+        for (key, _) in self.metadata.iter().flatten() {
+            if !keys.contains(&key.as_str()) {
+                keys.push(key);
+            }
+        }
+        keys
+    }
+
+    /// Taxa (internal id) with their abundance in each sample, highest summed abundance first
+    ///
+    /// A taxon absent from a sample has abundance 0 there.
+    pub fn rows(&self) -> Vec<(u32, Vec<Option<f64>>)> {
+        let mut taxa: Vec<u32> = self
+            .abundances
+            .iter()
+            .flat_map(|sample| sample.keys().copied())
+            .collect();
+        taxa.sort_unstable();
+        taxa.dedup();
+        let mut rows: Vec<(u32, Vec<Option<f64>>, f64)> = taxa
+            .into_iter()
+            .map(|taxid| {
+                let values: Vec<Option<f64>> = self
+                    .abundances
+                    .iter()
+                    .map(|sample| sample.get(&taxid).copied().unwrap_or(Some(0.0)))
+                    .collect();
+                let sum = values.iter().flatten().sum();
+                (taxid, values, sum)
+            })
+            .collect();
+        rows.sort_by(|a, b| b.2.total_cmp(&a.2).then(a.0.cmp(&b.0)));
+        rows.into_iter()
+            .map(|(taxid, values, _)| (taxid, values))
+            .collect()
+    }
+
+    /// Writes the matrix, `options` giving the rank and normalization of the abundances
+    pub fn write<P: AsRef<Path>>(
+        &self,
+        filename: P,
+        options: &AbundanceOptions,
+        taxonomy: &Taxonomy,
+    ) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(filename)?);
+        writeln!(writer, "# rank: {}", options.rank)?;
+        writeln!(
+            writer,
+            "# normalization: {}",
+            options.normalization.as_str()
+        )?;
+        writeln!(writer, "taxid\tname\t{}", self.labels.join("\t"))?;
+        for key in self.metadata_keys() {
+            let values: Vec<&str> = self
+                .metadata
+                .iter()
+                .map(|metadata| {
+                    metadata
+                        .iter()
+                        .find(|(k, _)| k == key)
+                        .map_or("", |(_, value)| value.as_str())
+                })
+                .collect();
+            writeln!(writer, "#{}\t\t{}", key, values.join("\t"))?;
+        }
+        for (taxid, values) in self.rows() {
+            let values: Vec<String> = values
+                .iter()
+                .map(|value| value.map_or_else(|| "NA".to_string(), |v| format!("{:.6}", v)))
+                .collect();
+            writeln!(
+                writer,
+                "{}\t{}\t{}",
+                taxonomy.nodes[taxid as usize].external_id,
+                taxonomy.name_of(taxid),
+                values.join("\t")
+            )?;
+        }
+        writer.flush()
+    }
+}
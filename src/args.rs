@@ -1,4 +1,7 @@
-use crate::utils::expand_spaced_seed_mask;
+use crate::abundance::Normalization;
+use crate::compact_hash::EvictionPolicy;
+use crate::heatmap::HeatmapFormat;
+use crate::utils::{expand_spaced_seed_mask, DiskCheck};
 use crate::{construct_seed_template, parse_binary};
 use clap::Parser;
 use seqkmer::Meros;
@@ -32,6 +35,11 @@ pub struct Build {
     /// Number of threads
     #[clap(short = 'p', long, default_value_t = num_cpus::get())]
     pub threads: usize,
+
+    /// Audit mode: collect data-quality warnings of the build, like sequences without
+    /// a taxid or malformed lines, into findings.json in the database directory
+    #[clap(long, action)]
+    pub audit: bool,
 }
 
 const BUFFER_SIZE: usize = 16 * 1024 * 1024;
@@ -58,6 +66,26 @@ pub struct ClassifyArgs {
     #[clap(long)]
     pub chunk_dir: PathBuf,
 
+    /// Regex with one capture group for the mate number (1 or 2) in read file names,
+    /// used to pair files expanded from directories and glob patterns.
+    /// Explicitly listed files are paired with it too when given.
+    #[clap(long)]
+    pub pair_regex: Option<String>,
+
+    /// Check the free space of chunk_dir against the estimated size of the chunk files before splitting
+    #[clap(long, value_enum, default_value_t = DiskCheck::Error)]
+    pub disk_check: DiskCheck,
+
+    /// Collapse exact duplicate reads (same sequence, both mates of a pair) into one,
+    /// their multiplicity is carried through to the read counts of the reports.
+    #[clap(long, action)]
+    pub dedup: bool,
+
+    /// Distinct reads tracked per sample by --dedup (about 50 bytes each),
+    /// once reached only copies of the reads already tracked are collapsed
+    #[clap(long, default_value_t = 10_000_000)]
+    pub dedup_max_reads: usize,
+
     /// File path for outputting normal Kraken output.
     #[clap(long = "output-dir", value_parser)]
     pub output_dir: Option<PathBuf>,
@@ -90,6 +118,37 @@ pub struct ClassifyArgs {
     #[clap(long, value_parser = clap::value_parser!(u32).range(1..=32), default_value_t = 4)]
     pub batch_size: u32,
 
+    /// Back the loaded hash pages with transparent huge pages (Linux only), falls back to regular pages if unavailable
+    #[clap(long, default_value_t = false)]
+    pub huge_pages: bool,
+
+    /// The number of hash pages kept in memory at the same time, each page takes hash_capacity * 4 bytes
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 1)]
+    pub page_cache_size: u64,
+
+    /// Eviction policy of the hash page cache
+    #[clap(long, value_enum, default_value_t = EvictionPolicy::Schedule)]
+    pub eviction_policy: EvictionPolicy,
+
+    /// Taxids of the page groups to load from a database built with --page-group-rank (comma separated),
+    /// default = all groups. Reads are classified against the selected clades only: a minimizer
+    /// found in several selected groups votes for the LCA of their taxa, one shared with a group
+    /// that is not selected only for its taxon in the selected groups.
+    #[clap(long, value_delimiter = ',')]
+    pub page_groups: Vec<u64>,
+
+    /// Chunk files with more slot data than this are split into byte ranges processed in parallel
+    #[clap(long, value_parser = parse_size, default_value = "4G")]
+    pub split_threshold: usize,
+
+    /// Buffer capacity of each per-sample output writer
+    #[clap(long, value_parser = parse_size, default_value = "8K")]
+    pub writer_buffer_size: usize,
+
+    /// Flush per-sample output writers once N seconds have passed since their last flush, checked whenever output is written; 0 flushes only when buffers are full
+    #[clap(long, default_value_t = 0)]
+    pub flush_interval: u64,
+
     /// Confidence score threshold
     #[clap(
         short = 'T',
@@ -116,12 +175,101 @@ pub struct ClassifyArgs {
     #[clap(short = 'z', long, value_parser, default_value_t = false)]
     pub report_zero_counts: bool,
 
+    /// Number of decimals of the percentage column in reports
+    #[clap(long, value_parser = clap::value_parser!(u8).range(0..=10), default_value_t = 2)]
+    pub report_precision: u8,
+
+    /// Separate report fields by tabs only: no padded percentages, no indented names
+    #[clap(long, value_parser, default_value_t = false)]
+    pub tab_only: bool,
+
+    /// Taxid written for unclassified reads in reports and per-read output
+    #[clap(long, value_parser, default_value_t = 0)]
+    pub unclassified_taxid: u32,
+
+    /// Report unclassified reads as a synthetic "unclassified" node under root
+    #[clap(long, value_parser, default_value_t = false)]
+    pub unclassified_under_root: bool,
+
+    /// Dynamic library with a per-read post-processing hook, can be repeated.
+    /// Requires the `dylib_plugins` feature.
+    #[clap(long = "plugin", value_parser)]
+    pub plugins: Vec<PathBuf>,
+
+    /// Tab separated sample sheet whose columns are copied into report and abundance table headers,
+    /// the abundance matrix and samples.json.
+    /// The first column names the sample by file index, input path or file name.
+    /// A spike_in column (taxid:quantity,...) declares spike-in controls for absolute abundances.
+    /// A negative_control column (true/false) marks blanks used to flag contaminants.
+    #[clap(long, value_parser)]
+    pub sample_metadata: Option<PathBuf>,
+
+    /// Export a taxon x read position heatmap of minimizer hits per sample with this many position bins
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..=1000), requires = "output_dir")]
+    pub heatmap_bins: Option<u64>,
+
+    /// File format of the hit heatmap
+    #[clap(long, value_enum, default_value_t = HeatmapFormat::Tsv)]
+    pub heatmap_format: HeatmapFormat,
+
+    /// Write the relative abundances of the taxa at this rank (e.g. species) per sample
+    /// to output_<i>.abundance.tsv, and for several samples a taxon x sample matrix
+    /// with the sample metadata to output_<min>-<max>.abundance_matrix.tsv
+    #[clap(long, requires = "output_dir")]
+    pub abundance_rank: Option<String>,
+
+    /// Denominator of the relative abundances
+    #[clap(long, value_enum, default_value_t = Normalization::Classified)]
+    pub abundance_normalization: Normalization,
+
+    /// Genome size table (taxid<TAB>bases) for genome-size normalization, default = $db/genome_sizes.tsv.
+    /// Databases built before the table was introduced need it passed explicitly.
+    #[clap(long, value_parser)]
+    pub genome_sizes: Option<PathBuf>,
+
+    /// Taxa whose share of the classified reads of a sample is at most this many times their
+    /// pooled share in the negative controls (negative_control column of the sample sheet) are
+    /// listed as likely contaminants. This is a plain sample-vs-blank share comparison, not
+    /// decontam's frequency method, which needs the input DNA concentration of each sample:
+    /// a contaminant that also makes up a large share of a sample is not flagged, and a
+    /// genuine taxon that is as rare in a sample as in the blanks is
+    #[clap(long, default_value_t = 2.0)]
+    pub contaminant_fold: f64,
+
+    /// Subtract the reads of each taxon in the negative controls, scaled by the ratio of the
+    /// sample's library size to the controls', from the other samples;
+    /// the subtracted reads are reported as unclassified, so the total stays unchanged
+    #[clap(long, action)]
+    pub subtract_controls: bool,
+
+    /// Add a minimizer fraction column to the reports: the estimated fraction of the distinct
+    /// reference minimizers of each clade that was hit in the sample. It counts minimizers, not
+    /// genome positions, so it is a proxy for rather than a measure of genome coverage breadth
+    #[clap(long, action)]
+    pub minimizer_fraction: bool,
+
+    /// Reference minimizer table (taxid<TAB>minimizers) for the minimizer fraction, default = $db/taxon_minimizers.tsv.
+    /// Databases built before the table was introduced need to be rebuilt.
+    #[clap(long, value_parser)]
+    pub taxon_minimizers: Option<PathBuf>,
+
+    /// Audit mode: collect parameter mismatches and data-quality warnings of the run
+    /// into findings.json in the output directory
+    #[clap(long, action, requires = "output_dir")]
+    pub audit: bool,
+
+    /// Continue a run interrupted by SIGINT/SIGTERM from the checkpoint.json in the chunk directory,
+    /// pass the same arguments as to the interrupted run
+    #[clap(long, action)]
+    pub resume: bool,
+
     // /// output file contains all unclassified sequence
     // #[clap(long, value_parser, default_value_t = false)]
     // pub full_output: bool,
     /// A list of input file paths (FASTA/FASTQ) to be processed by the classify program.
     /// Supports fasta or fastq format files (e.g., .fasta, .fastq) and gzip compressed files (e.g., .fasta.gz, .fastq.gz).
-    /// Can also be a single .txt file containing a list of input file paths, one per line.
+    /// Can also be a single .txt file containing a list of input file paths, one per line,
+    /// directories (searched recursively for read files) or quoted glob patterns.
     // #[clap(short = 'F', long = "files")]
     pub input_files: Vec<PathBuf>,
 }
@@ -0,0 +1,162 @@
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, BufWriter, Result};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Findings file written into the output directory in audit mode
+pub const FINDINGS_FILE: &str = "findings.json";
+
+/// Messages kept per finding, later occurrences are only counted
+const MAX_EXAMPLES: usize = 10;
+
+/// Findings of the current run, `None` unless audit mode is on
+static AUDIT: Mutex<Option<Findings>> = Mutex::new(None);
+
+/// A kind of soft warning raised by one stage, for one sample or the whole run
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Finding {
+    pub stage: String,
+    /// Short machine readable name, e.g. `missing_sequence_id` or `parameter_mismatch`
+    pub kind: String,
+    /// File index of the sample, `None` for run-wide findings
+    pub sample: Option<usize>,
+    /// Number of occurrences
+    pub count: u64,
+    /// The first messages of the finding
+    pub examples: Vec<String>,
+}
+
+/// Soft warnings collected during a run
+///
+/// Warnings of the same stage, kind and sample are merged into one finding that
+/// counts them and keeps the first few messages.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::audit::Findings;
+///
+/// let mut findings = Findings::default();
+/// findings.add("resolve", "missing_sequence_id", Some(1), "can't find 7 in sample_id map file");
+/// findings.add("resolve", "missing_sequence_id", Some(1), "can't find 9 in sample_id map file");
+/// findings.tally("splitr", "reads_without_minimizers", Some(1), 3, "3 reads without minimizers");
+/// findings.tally("splitr", "reads_without_minimizers", Some(1), 3, "3 reads without minimizers");
+///
+/// let all = findings.findings();
+/// assert_eq!(all.len(), 2);
+/// assert_eq!((all[0].count, all[0].examples.len()), (2, 2));
+/// assert_eq!((all[1].count, all[1].examples.len()), (3, 1));
+/// ```
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Findings {
+    findings: Vec<Finding>,
+}
+
+impl Findings {
+    fn entry(&mut self, stage: &str, kind: &str, sample: Option<usize>) -> &mut Finding {
+        let position = self
+            .findings
+            .iter()
+            .position(|f| f.stage == stage && f.kind == kind && f.sample == sample);
+        let index = position.unwrap_or_else(|| {
+            self.findings.push(Finding {
+                stage: stage.to_string(),
+                kind: kind.to_string(),
+                sample,
+                count: 0,
+                examples: Vec::new(),
+            });
+            self.findings.len() - 1
+        });
+        &mut self.findings[index]
+    }
+
+    /// Records one occurrence of a warning
+    pub fn add(&mut self, stage: &str, kind: &str, sample: Option<usize>, message: &str) {
+        let finding = self.entry(stage, kind, sample);
+        finding.count += 1;
+        if finding.examples.len() < MAX_EXAMPLES {
+            finding.examples.push(message.to_string());
+        }
+    }
+
+    /// Records the total of a counted event, replacing an earlier total of the same finding
+    ///
+    /// Stages that run more than once on the same input, like splitr for every page
+    /// group, report the same total again instead of adding to it.
+    pub fn tally(
+        &mut self,
+        stage: &str,
+        kind: &str,
+        sample: Option<usize>,
+        count: u64,
+        message: &str,
+    ) {
+        let finding = self.entry(stage, kind, sample);
+        finding.count = count;
+        finding.examples = vec![message.to_string()];
+    }
+
+    pub fn findings(&self) -> &[Finding] {
+        &self.findings
+    }
+}
+
+/// Starts collecting the soft warnings of this process
+pub fn enable_audit() {
+    let mut audit = AUDIT.lock().unwrap();
+    if audit.is_none() {
+        *audit = Some(Findings::default());
+    }
+}
+
+/// Prints a warning and records it when audit mode is on
+pub fn warn(stage: &str, kind: &str, sample: Option<usize>, message: &str) {
+    eprintln!("warning: {}", message);
+    if let Some(findings) = AUDIT.lock().unwrap().as_mut() {
+        findings.add(stage, kind, sample, message);
+    }
+}
+
+/// Records the total of a counted event when audit mode is on, see [`Findings::tally`]
+pub fn tally(stage: &str, kind: &str, sample: Option<usize>, count: u64, message: &str) {
+    if let Some(findings) = AUDIT.lock().unwrap().as_mut() {
+        findings.tally(stage, kind, sample, count, message);
+    }
+}
+
+/// Writes the findings collected so far to `findings.json` in `dir`
+///
+/// Returns `None` when audit mode is off.
+pub fn write_findings<P: AsRef<Path>>(dir: P) -> Result<Option<PathBuf>> {
+    let audit = AUDIT.lock().unwrap();
+    let findings = match audit.as_ref() {
+        Some(findings) => findings,
+        None => return Ok(None),
+    };
+    let filename = dir.as_ref().join(FINDINGS_FILE);
+    let writer = BufWriter::new(File::create(&filename)?);
+    serde_json::to_writer_pretty(writer, findings)?;
+    Ok(Some(filename))
+}
+
+/// Runs a stage and writes the findings collected so far to `dir` afterwards
+///
+/// The findings are written even when the stage fails, whose error is returned
+/// over a failure to write them. Does nothing extra when audit mode is off.
+pub fn with_findings<P, T, E, F>(dir: P, stage: F) -> std::result::Result<T, E>
+where
+    P: AsRef<Path>,
+    F: FnOnce() -> std::result::Result<T, E>,
+    E: From<io::Error>,
+{
+    let result = stage();
+    match write_findings(dir) {
+        Ok(Some(filename)) => println!("findings written to {}", filename.display()),
+        Ok(None) => {}
+        Err(e) if result.is_ok() => return Err(e.into()),
+        Err(e) => eprintln!("failed to write findings: {}", e),
+    }
+    result
+}
@@ -1,17 +1,29 @@
 use clap::Parser;
-use kun_peng::compact_hash::{read_next_page, Compact, HashConfig, Page, Row, Slot};
-use kun_peng::utils::{find_and_sort_files, open_file};
+use kun_peng::args::parse_size;
+use kun_peng::compact_hash::{
+    anon_huge_pages_kb, Compact, EvictionPolicy, HashConfig, Page, PageCache, Row, Slot,
+};
+use kun_peng::interrupt::{interrupted, interrupted_error, Interruptible};
+use kun_peng::utils::{find_and_sort_files, open_file, IntervalWriter, WriterConfig};
+use rayon::prelude::*;
 use seqkmer::buffer_read_parallel;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::{File, OpenOptions};
-use std::io::{self, BufReader, BufWriter, Read, Result, Write};
+use std::io::{self, BufReader, BufWriter, Read, Result, Seek, SeekFrom, Write};
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Mutex;
 use std::time::Instant;
 
 // 定义每批次处理的 Slot 数量
 pub const BUFFER_SIZE: usize = 48 * 1024 * 1024;
 
+// chunk 文件头: page index (u64) + chunk size (u64)
+const CHUNK_HEADER_SIZE: u64 = 16;
+
+// 并行处理单个 chunk 文件时，同时打开的 writer 数量上限
+const MAX_OPEN_WRITERS: usize = 256;
+
 /// Command line arguments for the splitr program.
 ///
 /// This structure defines the command line arguments that are accepted by the splitr program.
@@ -41,6 +53,34 @@ pub struct Args {
     /// The number of threads to use.
     #[clap(short = 'p', long = "num-threads", value_parser, default_value_t = num_cpus::get())]
     pub num_threads: usize,
+
+    /// Back the loaded hash pages with transparent huge pages (Linux only), falls back to regular pages if unavailable
+    #[clap(long, default_value_t = false)]
+    pub huge_pages: bool,
+
+    /// The number of hash pages kept in memory at the same time, each page takes hash_capacity * 4 bytes
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 1)]
+    pub page_cache_size: u64,
+
+    /// Eviction policy of the hash page cache
+    #[clap(long, value_enum, default_value_t = EvictionPolicy::Schedule)]
+    pub eviction_policy: EvictionPolicy,
+
+    /// Chunk files with more slot data than this are split into byte ranges processed in parallel
+    #[clap(long, value_parser = parse_size, default_value = "4G")]
+    pub split_threshold: usize,
+
+    /// Buffer capacity of each per-sample output writer
+    #[clap(long, value_parser = parse_size, default_value = "8K")]
+    pub writer_buffer_size: usize,
+
+    /// Flush per-sample output writers once N seconds have passed since their last flush, checked whenever output is written; 0 flushes only when buffers are full
+    #[clap(long, default_value_t = 0)]
+    pub flush_interval: u64,
+
+    /// Continue an interrupted run, the chunk files it finished were already removed
+    #[clap(skip)]
+    pub resume: bool,
 }
 
 fn read_chunk_header<R: Read>(reader: &mut R) -> io::Result<(usize, usize)> {
@@ -96,8 +136,9 @@ fn write_to_file(
     file_index: u64,
     seq_id_mod: u32,
     bytes: &[u8],
-    writers: &mut HashMap<(u64, u32), BufWriter<File>>,
+    writers: &mut SampleWriters,
     chunk_dir: &PathBuf,
+    writer_config: &WriterConfig,
 ) -> io::Result<()> {
     // 检查是否已经有该文件的 writer，没有则创建一个新的
     let writer = writers.entry((file_index, seq_id_mod)).or_insert_with(|| {
@@ -108,7 +149,7 @@ fn write_to_file(
             .append(true)
             .open(&file_path)
             .expect("failed to open file");
-        BufWriter::new(file)
+        writer_config.writer(file)
     });
 
     writer.write_all(bytes)?;
@@ -116,10 +157,7 @@ fn write_to_file(
     Ok(())
 }
 
-fn clean_up_writers(
-    writers: &mut HashMap<(u64, u32), BufWriter<File>>,
-    current_file_index: u64,
-) -> io::Result<()> {
+fn clean_up_writers(writers: &mut SampleWriters, current_file_index: u64) -> io::Result<()> {
     let keys_to_remove: Vec<(u64, u32)> = writers
         .keys()
         .cloned()
@@ -135,57 +173,70 @@ fn clean_up_writers(
     Ok(())
 }
 
-fn process_batch<R>(
-    reader: &mut R,
+type SampleWriters = HashMap<(u64, u32), IntervalWriter<File>>;
+
+/// Shared inputs for annotating the slots of one chunk file
+struct BatchContext<'a> {
+    hash_config: &'a HashConfig,
+    page: &'a Page,
+    chunk_dir: &'a PathBuf,
+    buffer_size: usize,
+    bin_threads: u32,
+    writer_config: WriterConfig,
+}
+
+/// Looks up a batch of slots in the page and groups the hit rows by (file_index, seq_id_mod)
+fn annotate_slots(
+    slots: &[Slot<u64>],
     hash_config: &HashConfig,
     page: &Page,
-    chunk_dir: PathBuf,
-    buffer_size: usize,
     bin_threads: u32,
-    // page_index: usize,
-    num_threads: usize,
-) -> std::io::Result<()>
-where
-    R: Read + Send,
-{
+) -> HashMap<(u64, u32), Vec<u8>> {
     let row_size = std::mem::size_of::<Row>();
-    let mut writers: HashMap<(u64, u32), BufWriter<File>> = HashMap::new();
-    let mut current_file_index: Option<u64> = None;
-
     let value_mask = hash_config.get_value_mask();
     let value_bits = hash_config.get_value_bits();
     let idx_mask = hash_config.get_idx_mask();
     let idx_bits = hash_config.get_idx_bits();
 
+    let mut results: HashMap<(u64, u32), Vec<u8>> = HashMap::new();
+    for slot in slots {
+        let indx = slot.idx & idx_mask;
+        let compacted = slot.value.left(value_bits) as u32;
+        // let taxid = chtm.get_from_page(indx, compacted, page_index);
+        let taxid = page.find_index(indx, compacted, value_bits, value_mask);
+
+        if taxid > 0 {
+            let kmer_id = slot.idx >> idx_bits;
+            let file_index = slot.value.right(value_mask) >> 32;
+            let seq_id = slot.get_seq_id() as u32;
+            let left = slot.value.left(value_bits) as u32;
+            let high = u32::combined(left, taxid, value_bits);
+            let row = Row::new(high, seq_id, kmer_id as u32);
+            let value_bytes = row.as_slice(row_size);
+            let seq_id_mod = seq_id % bin_threads;
+
+            results
+                .entry((file_index, seq_id_mod))
+                .or_insert_with(Vec::new)
+                .extend(value_bytes);
+        }
+    }
+    results
+}
+
+fn process_batch<R>(reader: &mut R, ctx: &BatchContext, num_threads: usize) -> std::io::Result<()>
+where
+    R: Read + Send,
+{
+    let mut writers: SampleWriters = HashMap::new();
+    let mut current_file_index: Option<u64> = None;
+
     buffer_read_parallel(
         reader,
         num_threads,
-        buffer_size,
+        ctx.buffer_size,
         |dataset: Vec<Slot<u64>>| {
-            let mut results: HashMap<(u64, u32), Vec<u8>> = HashMap::new();
-            for slot in dataset {
-                let indx = slot.idx & idx_mask;
-                let compacted = slot.value.left(value_bits) as u32;
-                // let taxid = chtm.get_from_page(indx, compacted, page_index);
-                let taxid = page.find_index(indx, compacted, value_bits, value_mask);
-
-                if taxid > 0 {
-                    let kmer_id = slot.idx >> idx_bits;
-                    let file_index = slot.value.right(value_mask) >> 32;
-                    let seq_id = slot.get_seq_id() as u32;
-                    let left = slot.value.left(value_bits) as u32;
-                    let high = u32::combined(left, taxid, value_bits);
-                    let row = Row::new(high, seq_id, kmer_id as u32);
-                    let value_bytes = row.as_slice(row_size);
-                    let seq_id_mod = seq_id % bin_threads;
-
-                    results
-                        .entry((file_index, seq_id_mod))
-                        .or_insert_with(Vec::new)
-                        .extend(value_bytes);
-                }
-            }
-            results
+            annotate_slots(&dataset, ctx.hash_config, ctx.page, ctx.bin_threads)
         },
         |result| {
             while let Some(data) = result.next() {
@@ -201,10 +252,21 @@ where
                             current_file_index = Some(file_index);
                         }
 
-                        write_to_file(file_index, seq_id_mod, bytes, &mut writers, &chunk_dir)
-                            .expect("write to file error");
+                        write_to_file(
+                            file_index,
+                            seq_id_mod,
+                            bytes,
+                            &mut writers,
+                            ctx.chunk_dir,
+                            &ctx.writer_config,
+                        )
+                        .expect("write to file error");
                     }
                 }
+                // 本批次没有写入的 writer 也按间隔刷新
+                for writer in writers.values_mut() {
+                    writer.flush_if_due().expect("flush writer error");
+                }
             }
         },
     )
@@ -218,13 +280,145 @@ where
     Ok(())
 }
 
+/// Splits `data_len` bytes starting at `data_start` into at most `parts` ranges on slot boundaries
+///
+/// Returns (offset, length) pairs; a trailing partial slot is dropped like in the sequential reader.
+fn split_slot_ranges(
+    data_start: u64,
+    data_len: u64,
+    slot_size: u64,
+    parts: usize,
+) -> Vec<(u64, u64)> {
+    let slots = data_len / slot_size;
+    let parts = (parts as u64).clamp(1, slots.max(1));
+    let slots_per_range = slots.div_ceil(parts);
+
+    let mut ranges = Vec::new();
+    let mut slot = 0;
+    while slot < slots {
+        let count = slots_per_range.min(slots - slot);
+        ranges.push((data_start + slot * slot_size, count * slot_size));
+        slot += count;
+    }
+    ranges
+}
+
+/// Annotates one byte range of a chunk file, appending the rows to the shared sample writers
+fn process_range(
+    ctx: &BatchContext,
+    chunk_file: &Path,
+    range: (u64, u64),
+    writers: &Mutex<SampleWriters>,
+) -> Result<()> {
+    let slot_size = std::mem::size_of::<Slot<u64>>();
+    let (offset, length) = range;
+
+    let mut file = open_file(chunk_file)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut reader = BufReader::new(file.take(length));
+
+    let mut remaining = length as usize;
+    let batch_slots = ctx.buffer_size.min(remaining / slot_size).max(1);
+    let mut batch_buffer = vec![0u8; slot_size * batch_slots];
+    while remaining > 0 && interrupted().is_none() {
+        let bytes_to_read = remaining.min(batch_buffer.len());
+        reader.read_exact(&mut batch_buffer[..bytes_to_read])?;
+        remaining -= bytes_to_read;
+
+        let slots = unsafe {
+            std::slice::from_raw_parts(
+                batch_buffer.as_ptr() as *const Slot<u64>,
+                bytes_to_read / slot_size,
+            )
+        };
+        let res = annotate_slots(slots, ctx.hash_config, ctx.page, ctx.bin_threads);
+
+        let mut file_keys: Vec<_> = res.keys().cloned().collect();
+        file_keys.sort_unstable();
+
+        let mut writers = writers.lock().unwrap();
+        if writers.len() > MAX_OPEN_WRITERS {
+            clean_up_writers(&mut writers, u64::MAX)?;
+        }
+        for (file_index, seq_id_mod) in file_keys {
+            if let Some(bytes) = res.get(&(file_index, seq_id_mod)) {
+                write_to_file(
+                    file_index,
+                    seq_id_mod,
+                    bytes,
+                    &mut writers,
+                    ctx.chunk_dir,
+                    &ctx.writer_config,
+                )?;
+            }
+        }
+        // 本批次没有写入的 writer 也按间隔刷新
+        for writer in writers.values_mut() {
+            writer.flush_if_due()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Annotates a large chunk file by processing slot-aligned byte ranges in parallel
+///
+/// Rows are still routed to the `sample_file_{file_index}_{seq_id_mod}.bin` file of
+/// their sample; only the order of rows inside a bin file differs from the
+/// sequential path, which resolve does not depend on.
+fn process_ranges(
+    ctx: &BatchContext,
+    chunk_file: &Path,
+    ranges: &[(u64, u64)],
+    num_threads: usize,
+) -> Result<()> {
+    let writers: Mutex<SampleWriters> = Mutex::new(HashMap::new());
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build()
+        .map_err(io::Error::other)?;
+
+    pool.install(|| {
+        ranges
+            .par_iter()
+            .try_for_each(|range| process_range(ctx, chunk_file, *range, &writers))
+    })?;
+
+    for writer in writers.into_inner().unwrap().values_mut() {
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Reads the page index of every chunk file, used to plan the page loading order
+fn scan_chunk_headers(chunk_files: &[PathBuf]) -> Result<Vec<(PathBuf, usize)>> {
+    let mut headers = Vec::with_capacity(chunk_files.len());
+    for chunk_file in chunk_files {
+        let mut reader = BufReader::new(open_file(chunk_file)?);
+        let (page_index, _) = read_chunk_header(&mut reader)?;
+        headers.push((chunk_file.clone(), page_index));
+    }
+    Ok(headers)
+}
+
+/// Groups chunk files by page index so each hash page is loaded once per run
+fn plan_page_order(chunk_headers: Vec<(PathBuf, usize)>) -> BTreeMap<usize, Vec<PathBuf>> {
+    let mut plan: BTreeMap<usize, Vec<PathBuf>> = BTreeMap::new();
+    for (chunk_file, page_index) in chunk_headers {
+        plan.entry(page_index).or_default().push(chunk_file);
+    }
+    plan
+}
+
 fn process_chunk_file<P: AsRef<Path>>(
     args: &Args,
     chunk_file: P,
     hash_files: &Vec<PathBuf>,
-    large_page: &mut Page,
+    page_cache: &mut PageCache,
 ) -> Result<()> {
-    let file = open_file(chunk_file)?;
+    let file = open_file(&chunk_file)?;
+    let file_size = file.metadata()?.len();
     let mut reader = BufReader::new(file);
 
     let (page_index, _) = read_chunk_header(&mut reader)?;
@@ -232,45 +426,94 @@ fn process_chunk_file<P: AsRef<Path>>(
     let start = Instant::now();
 
     println!("start load table...");
-    let config = HashConfig::from_hash_header(&args.database.join("hash_config.k2d"))?;
-
-    read_next_page(large_page, hash_files, page_index, config)?;
+    let config = *page_cache.config();
+    let (page, loaded) = page_cache.get(hash_files, page_index)?;
     // 计算持续时间
     let duration = start.elapsed();
     // 打印运行时间
-    println!("load table took: {:?}", duration);
-    process_batch(
-        &mut reader,
-        &config,
-        &large_page,
-        args.chunk_dir.clone(),
-        args.buffer_size,
-        args.batch_size,
-        // page_index,
-        args.num_threads,
-    )?;
+    if loaded {
+        println!("load table took: {:?}", duration);
+    } else {
+        println!("table {} already loaded", page_index);
+    }
+
+    let mut ctx = BatchContext {
+        hash_config: &config,
+        page,
+        chunk_dir: &args.chunk_dir,
+        buffer_size: args.buffer_size,
+        bin_threads: args.batch_size,
+        writer_config: WriterConfig::new(args.writer_buffer_size, args.flush_interval),
+    };
+
+    let data_len = file_size.saturating_sub(CHUNK_HEADER_SIZE);
+    if data_len > args.split_threshold as u64 && args.num_threads > 1 {
+        let slot_size = std::mem::size_of::<Slot<u64>>() as u64;
+        let ranges = split_slot_ranges(CHUNK_HEADER_SIZE, data_len, slot_size, args.num_threads);
+        println!(
+            "split chunk file {:?} into {} ranges",
+            chunk_file.as_ref(),
+            ranges.len()
+        );
+        // keep the total buffer size of all ranges at `buffer_size` slots
+        ctx.buffer_size = (args.buffer_size / ranges.len()).max(1);
+        process_ranges(&ctx, chunk_file.as_ref(), &ranges, args.num_threads)?;
+    } else {
+        process_batch(&mut Interruptible::new(reader), &ctx, args.num_threads)?;
+    }
 
     Ok(())
 }
 
 pub fn run(args: Args) -> Result<()> {
-    let chunk_files = find_and_sort_files(&args.chunk_dir, "sample", ".k2", true)?;
+    let chunk_files = find_and_sort_files(&args.chunk_dir, "sample", ".k2", !args.resume)?;
     let hash_files = find_and_sort_files(&args.database, "hash", ".k2d", true)?;
 
     // 开始计时
     let start = Instant::now();
     println!("annotate start...");
     let config = HashConfig::from_hash_header(&args.database.join("hash_config.k2d"))?;
-    let mut large_page = Page::with_capacity(0, config.hash_capacity);
-    for chunk_file in &chunk_files {
-        process_chunk_file(&args, chunk_file, &hash_files, &mut large_page)?;
-        let _ = std::fs::remove_file(chunk_file);
+    let chunk_headers = scan_chunk_headers(&chunk_files)?;
+    let mut page_cache = PageCache::new(
+        config,
+        args.page_cache_size as usize,
+        args.eviction_policy,
+        args.huge_pages,
+    );
+    page_cache.schedule(chunk_headers.iter().map(|(_, page_index)| *page_index));
+    let page_plan = plan_page_order(chunk_headers);
+
+    for (page_index, page_chunk_files) in &page_plan {
+        println!(
+            "page {}: {} chunk file(s)",
+            page_index,
+            page_chunk_files.len()
+        );
+        for chunk_file in page_chunk_files {
+            process_chunk_file(&args, chunk_file, &hash_files, &mut page_cache)?;
+            if interrupted().is_some() {
+                // 保留未处理完的 chunk 文件, 它对应的 bin 文件只写入了部分数据
+                eprintln!(
+                    "annotate interrupted while processing {:?}, its rows in the bin files are partial",
+                    chunk_file
+                );
+                return Err(interrupted_error("annotate"));
+            }
+            let _ = std::fs::remove_file(chunk_file);
+        }
     }
 
     // 计算持续时间
     let duration = start.elapsed();
     // 打印运行时间
     println!("annotate took: {:?}", duration);
+    if args.huge_pages {
+        // 以内核实际分配的大页为准, madvise 成功并不代表页面已经使用了大页
+        match anon_huge_pages_kb() {
+            Some(kb) if kb > 0 => println!("huge pages: {} kB backed by huge pages", kb),
+            _ => println!("huge pages: unavailable, fell back to regular pages"),
+        }
+    }
 
     Ok(())
 }
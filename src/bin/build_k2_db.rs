@@ -1,9 +1,11 @@
 // 使用时需要引用模块路径
 use clap::Parser;
 use kun_peng::compact_hash::HashConfig;
+use kun_peng::coverage::{write_taxon_minimizers, TAXON_MINIMIZERS_FILENAME};
 use kun_peng::db::process_k2file;
 use kun_peng::taxonomy::Taxonomy;
 use kun_peng::utils::find_and_trans_files;
+use std::collections::HashMap;
 use std::fs::remove_file;
 use std::path::PathBuf;
 use std::time::Instant;
@@ -30,6 +32,7 @@ pub fn run(database: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
     let chunk_files = find_and_trans_files(&k2d_dir, "chunk", ".k2", true)?;
 
     let mut size: usize = 0;
+    let mut taxon_cells: HashMap<u32, u64> = HashMap::new();
 
     println!("start process k2 files...");
     for (i, chunk_file) in &chunk_files {
@@ -41,6 +44,7 @@ pub fn run(database: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
             &taxonomy,
             hash_config.hash_capacity,
             *i,
+            &mut taxon_cells,
         )?;
         size += count;
         let duration = start.elapsed();
@@ -53,6 +57,13 @@ pub fn run(database: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
     hash_config.size = size;
     hash_config.write_to_file(&hash_filename)?;
 
+    // 每个分类单元在哈希表中占有的 minimizer 数, 用于估计覆盖广度
+    let taxon_minimizers: HashMap<u64, u64> = taxon_cells
+        .into_iter()
+        .map(|(taxid, count)| (taxonomy.nodes[taxid as usize].external_id, count))
+        .collect();
+    write_taxon_minimizers(k2d_dir.join(TAXON_MINIMIZERS_FILENAME), &taxon_minimizers)?;
+
     // 计算持续时间
     let duration = start.elapsed();
     // 打印运行时间
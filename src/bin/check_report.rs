@@ -0,0 +1,117 @@
+use clap::Parser;
+use kun_peng::report_check::{check_entries, read_heatmap, CheckThresholds};
+use kun_peng::report_import::{read_report, ReportFormat, RANKS};
+use std::fs::File;
+use std::io::{self, BufWriter, Result, Write};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Flag biologically implausible taxa in a kun_peng report",
+    long_about = "Flag biologically implausible taxa in a kun_peng report.\nAbundant taxa whose reads hit only a few distinct minimizers (report written with -K), that cover next to none of their reference minimizers (report written with --minimizer-fraction) or whose hits cluster at a few read positions (--heatmap, written by --heatmap-bins) are likely false positives."
+)]
+pub struct Args {
+    /// Kraken style report of one sample (output_<i>.kreport2)
+    pub report: PathBuf,
+
+    /// Hit heatmap of the same sample in TSV format (heatmap_<i>.tsv), enables the read position checks
+    #[clap(long)]
+    pub heatmap: Option<PathBuf>,
+
+    /// Rank to check
+    #[clap(long, default_value = "species", value_parser = clap::builder::PossibleValuesParser::new(RANKS))]
+    pub rank: String,
+
+    /// Only check taxa with at least this abundance (percent of all reads)
+    #[clap(long, default_value_t = 1.0)]
+    pub min_abundance: f64,
+
+    /// Flag taxa with fewer distinct minimizers
+    #[clap(long, default_value_t = 100)]
+    pub min_distinct_minimizers: u64,
+
+    /// Flag taxa whose minimizer fraction (fraction of reference minimizers observed) is below this
+    #[clap(long, default_value_t = 0.001)]
+    pub min_minimizer_fraction: f64,
+
+    /// Flag taxa whose hit position evenness (normalized entropy, 0..1) is below this
+    #[clap(long, default_value_t = 0.5)]
+    pub min_evenness: f64,
+
+    /// Flag taxa whose first and last position bin hold more than this many times their even share of the hits
+    #[clap(long, default_value_t = 3.0)]
+    pub max_end_enrichment: f64,
+
+    /// Write the findings to this file instead of stdout
+    #[clap(long, short)]
+    pub output: Option<PathBuf>,
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let entries = read_report(&args.report, Some(ReportFormat::Kraken))?;
+    if entries
+        .iter()
+        .all(|entry| entry.distinct_minimizers.is_none())
+    {
+        eprintln!("report has no minimizer columns, write it with -K to check distinct minimizers");
+    }
+    if entries.iter().all(|entry| entry.minimizer_fraction.is_none()) {
+        eprintln!(
+            "report has no minimizer fraction column, write it with --minimizer-fraction to check it"
+        );
+    }
+    let heatmap = match &args.heatmap {
+        Some(filename) => Some(read_heatmap(filename)?),
+        None => None,
+    };
+    let thresholds = CheckThresholds {
+        min_abundance: args.min_abundance,
+        min_distinct_minimizers: args.min_distinct_minimizers,
+        min_minimizer_fraction: args.min_minimizer_fraction,
+        min_evenness: args.min_evenness,
+        max_end_enrichment: args.max_end_enrichment,
+    };
+    let findings = check_entries(&entries, &args.rank, heatmap.as_ref(), &thresholds);
+
+    let mut writer: Box<dyn Write> = match &args.output {
+        Some(filename) => Box::new(BufWriter::new(File::create(filename)?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+    writeln!(writer, "taxid\tname\treads\tabundance\tcheck\tdetail")?;
+    for finding in &findings {
+        let na = || "NA".to_string();
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{:.2}\t{}\t{}",
+            finding.taxid.map_or_else(na, |taxid| taxid.to_string()),
+            finding.name,
+            finding.reads.map_or_else(na, |reads| reads.to_string()),
+            finding.abundance,
+            finding.check,
+            finding.detail
+        )?;
+    }
+    writer.flush()?;
+
+    let checked = entries
+        .iter()
+        .filter(|entry| entry.rank == Some(args.rank.as_str()))
+        .filter(|entry| entry.abundance >= args.min_abundance)
+        .count();
+    eprintln!(
+        "{} taxa at rank {} checked, {} findings",
+        checked,
+        args.rank,
+        findings.len()
+    );
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
+}
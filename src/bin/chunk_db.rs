@@ -1,6 +1,7 @@
 // 使用时需要引用模块路径
 use clap::Parser;
 use kun_peng::args::{parse_size, Build};
+use kun_peng::audit::{enable_audit, with_findings};
 use kun_peng::compact_hash::HashConfig;
 use kun_peng::db::{convert_fna_to_k2_format, get_bits_for_taxid};
 use kun_peng::taxonomy::Taxonomy;
@@ -100,7 +101,11 @@ struct ChunkArgs {
 #[allow(dead_code)]
 fn main() {
     let args = ChunkArgs::parse();
-    if let Err(e) = run(args.db_args, args.required_capacity) {
+    if args.db_args.build.audit {
+        enable_audit();
+    }
+    let database = args.db_args.build.database.clone();
+    if let Err(e) = with_findings(database, || run(args.db_args, args.required_capacity)) {
         eprintln!("Application error: {}", e);
     }
 }
@@ -0,0 +1,124 @@
+use clap::Parser;
+use kun_peng::report_import::{
+    bray_curtis, match_names, rank_profile, read_report, taxid_names, ReportFormat, RANKS,
+};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{self, BufWriter, Error, ErrorKind, Result, Write};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Compare taxonomic profiles of kun_peng and other classifiers",
+    long_about = "Compare taxonomic profiles of the same sample reported by kun_peng, Kraken 2, Centrifuge or MetaPhlAn.\nAbundances are renormalized to 100% at the chosen rank, taxa are matched by taxid, taxa of reports without taxids by name to the taxids of the other reports."
+)]
+pub struct Args {
+    /// Reports to compare, the first one is the reference
+    #[clap(required = true, num_args = 2..)]
+    pub reports: Vec<PathBuf>,
+
+    /// Format of the reports, detected from the first line of each report by default
+    #[clap(long, value_enum)]
+    pub format: Option<ReportFormat>,
+
+    /// Rank to compare at
+    #[clap(long, default_value = "species", value_parser = clap::builder::PossibleValuesParser::new(RANKS))]
+    pub rank: String,
+
+    /// Taxa below this abundance (percent at the rank) count as absent
+    #[clap(long, default_value_t = 0.0)]
+    pub min_abundance: f64,
+
+    /// Write the taxon x report abundance matrix to this file instead of stdout
+    #[clap(long, short)]
+    pub output: Option<PathBuf>,
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let mut rank_profiles = Vec::with_capacity(args.reports.len());
+    for report in &args.reports {
+        let entries = read_report(report, args.format)?;
+        let profile = rank_profile(&entries, &args.rank);
+        if profile.is_empty() {
+            eprintln!("{:?} has no taxa at rank {}", report, args.rank);
+        }
+        rank_profiles.push(profile);
+    }
+
+    // 没有 taxid 的报告按名字对应到其他报告里的 taxid
+    let taxids = taxid_names(&rank_profiles);
+    let mut profiles = Vec::with_capacity(rank_profiles.len());
+    let mut names: HashMap<String, String> = HashMap::new();
+    for profile in rank_profiles {
+        let mut values = HashMap::new();
+        for (key, (name, abundance)) in match_names(profile, &taxids) {
+            if abundance >= args.min_abundance {
+                names.entry(key.clone()).or_insert(name);
+                values.insert(key, abundance);
+            }
+        }
+        profiles.push(values);
+    }
+
+    if profiles.iter().all(|profile| profile.is_empty()) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("no taxa at rank {} in any report", args.rank),
+        ));
+    }
+
+    // taxa sorted by the abundance in the reference report, then by name and key
+    let mut rows: BTreeSet<(i64, String, String)> = BTreeSet::new();
+    for (key, name) in &names {
+        let reference = profiles[0].get(key).copied().unwrap_or(0.0);
+        rows.insert((-(reference * 1e6) as i64, name.clone(), key.clone()));
+    }
+
+    let mut writer: Box<dyn Write> = match &args.output {
+        Some(filename) => Box::new(BufWriter::new(File::create(filename)?)),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+    write!(writer, "taxon\tname")?;
+    for report in &args.reports {
+        write!(writer, "\t{}", report.display())?;
+    }
+    writeln!(writer)?;
+    for (_, name, key) in &rows {
+        write!(writer, "{}\t{}", key, name)?;
+        for profile in &profiles {
+            write!(writer, "\t{:.4}", profile.get(key).copied().unwrap_or(0.0))?;
+        }
+        writeln!(writer)?;
+    }
+    writer.flush()?;
+
+    let reference = &profiles[0];
+    eprintln!("report\ttaxa\tshared\tprecision\trecall\tbray_curtis");
+    for (report, profile) in args.reports.iter().zip(&profiles) {
+        let shared = profile
+            .keys()
+            .filter(|k| reference.contains_key(*k))
+            .count();
+        let ratio = |n: usize, d: usize| if d > 0 { n as f64 / d as f64 } else { 0.0 };
+        eprintln!(
+            "{}\t{}\t{}\t{:.4}\t{:.4}\t{:.4}",
+            report.display(),
+            profile.len(),
+            shared,
+            ratio(shared, profile.len()),
+            ratio(shared, reference.len()),
+            bray_curtis(reference, profile)
+        );
+    }
+
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
+}
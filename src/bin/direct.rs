@@ -1,19 +1,38 @@
 use clap::Parser;
-use kun_peng::classify::process_hitgroup;
-use kun_peng::compact_hash::{CHTable, Compact, HashConfig, Row};
+use kun_peng::audit::{enable_audit, warn, write_findings};
+use kun_peng::classify::{compare_calls, process_hitgroup};
+use kun_peng::compact_hash::{anon_huge_pages_kb, CHTable, Compact, HashConfig, Row};
+use kun_peng::coverage::{load_clade_minimizers, TAXON_MINIMIZERS_FILENAME};
+use kun_peng::inputs::{
+    barcode_of, expand_input_files, pair_read_files, DEFAULT_BARCODE_REGEX, DEFAULT_PAIR_REGEX,
+};
+use kun_peng::interrupt::{
+    clear_incomplete_marker, interrupted, interrupted_error, write_incomplete_marker, Interruptible,
+};
+use kun_peng::metadata::SampleMetadata;
+use kun_peng::page_group::{database_dirs, merge_group_rows};
+use kun_peng::plugin::{PostProcessors, ReadCall};
 use kun_peng::readcounts::{TaxonCounters, TaxonCountersDash};
-use kun_peng::report::report_kraken_style;
+use kun_peng::report::{report_kraken_style, ReportOptions};
 use kun_peng::taxonomy::Taxonomy;
 use kun_peng::utils::{create_sample_file, find_and_sort_files, get_lastest_file_index};
 use kun_peng::{HitGroup, IndexOptions};
+use regex::Regex;
 use seqkmer::{read_parallel, Base, FastxReader, Meros, MinimizerIterator, OptionPair, Reader};
-use std::collections::HashMap;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::{self, BufWriter, Write};
 use std::io::{Error, ErrorKind, Result};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::Instant;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Default seconds between two scans of the watched directory
+const DEFAULT_WATCH_INTERVAL: u64 = 10;
+/// Default idle timeout of --watch, 0 watches until interrupted
+const DEFAULT_WATCH_TIMEOUT: u64 = 0;
 
 #[derive(Parser, Debug, Clone)]
 #[clap(
@@ -26,6 +45,18 @@ pub struct Args {
     #[arg(long = "db", required = true)]
     pub database: PathBuf,
 
+    /// Second database to classify every read against as well (ensemble mode).
+    /// Its call and the agreement of both calls are appended to the per-read output.
+    #[arg(long = "db2")]
+    pub database2: Option<PathBuf>,
+
+    /// Taxids of the page groups to load from a database built with --page-group-rank (comma separated),
+    /// default = all groups. Reads are classified against the selected clades only: a minimizer
+    /// found in several loaded groups votes for the LCA of their taxa, one shared with a group
+    /// that is not loaded only for its taxon in the loaded groups. All groups of --db2 are loaded.
+    #[arg(long, value_delimiter = ',')]
+    pub page_groups: Vec<u64>,
+
     /// File path for outputting normal Kraken output.
     #[clap(long = "output-dir", value_parser)]
     pub output_dir: Option<PathBuf>,
@@ -64,6 +95,27 @@ pub struct Args {
     #[clap(short = 'z', long, value_parser, default_value_t = false)]
     pub report_zero_counts: bool,
 
+    /// Number of decimals of the percentage column in reports
+    #[clap(long, value_parser = clap::value_parser!(u8).range(0..=10), default_value_t = 2)]
+    pub report_precision: u8,
+
+    /// Separate report fields by tabs only: no padded percentages, no indented names
+    #[clap(long, value_parser, default_value_t = false)]
+    pub tab_only: bool,
+
+    /// Taxid written for unclassified reads in reports and per-read output
+    #[clap(long, value_parser, default_value_t = 0)]
+    pub unclassified_taxid: u32,
+
+    /// Report unclassified reads as a synthetic "unclassified" node under root
+    #[clap(long, value_parser, default_value_t = false)]
+    pub unclassified_under_root: bool,
+
+    /// Dynamic library with a per-read post-processing hook, can be repeated.
+    /// Requires the `dylib_plugins` feature.
+    #[clap(long = "plugin", value_parser)]
+    pub plugins: Vec<PathBuf>,
+
     /// The minimum number of hit groups needed for a call.
     #[clap(
         short = 'g',
@@ -77,90 +129,241 @@ pub struct Args {
     #[clap(short = 'p', long = "num-threads", value_parser, default_value_t = num_cpus::get())]
     pub num_threads: usize,
 
+    /// Back the loaded hash pages with transparent huge pages (Linux only), falls back to regular pages if unavailable
+    #[clap(long, default_value_t = false)]
+    pub huge_pages: bool,
+
+    /// Regex with one capture group for the mate number (1 or 2) in read file names,
+    /// used to pair files expanded from directories and glob patterns.
+    /// Explicitly listed files are paired with it too when given.
+    #[clap(long)]
+    pub pair_regex: Option<String>,
+
+    /// After the input files, keep watching this directory (e.g. a MinKNOW fastq_pass directory)
+    /// and classify new read files as they arrive, until interrupted
+    #[clap(long, requires = "output_dir")]
+    pub watch: Option<PathBuf>,
+
+    /// Seconds between two scans of the watched directory,
+    /// a file is classified once its size is unchanged between two scans
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = DEFAULT_WATCH_INTERVAL)]
+    pub watch_interval: u64,
+
+    /// Stop watching after this many seconds without new files, 0 watches until interrupted
+    #[clap(long, default_value_t = DEFAULT_WATCH_TIMEOUT)]
+    pub watch_timeout: u64,
+
+    /// Keep a cumulative report per barcode (output_<barcode>.kreport2), rewritten after every file
+    #[clap(long, action, requires = "output_dir")]
+    pub barcode_reports: bool,
+
+    /// Regex finding the barcode in read file paths, the last match (its first capture group if any) is used
+    #[clap(long, default_value = DEFAULT_BARCODE_REGEX)]
+    pub barcode_regex: String,
+
+    /// Add a minimizer fraction column to the reports: the estimated fraction of the distinct
+    /// reference minimizers of each clade that was hit in the sample. It counts minimizers, not
+    /// genome positions, so it is a proxy for rather than a measure of genome coverage breadth
+    #[clap(long, action)]
+    pub minimizer_fraction: bool,
+
+    /// Reference minimizer table (taxid<TAB>minimizers) for the minimizer fraction, default = $db/taxon_minimizers.tsv.
+    /// Databases built before the table was introduced need to be rebuilt.
+    #[clap(long, value_parser)]
+    pub taxon_minimizers: Option<PathBuf>,
+
+    /// Audit mode: collect parameter mismatches and data-quality warnings of the run
+    /// into findings.json in the output directory
+    #[clap(long, action, requires = "output_dir")]
+    pub audit: bool,
+
+    /// Tab separated sample sheet whose columns are copied into report headers and samples.json.
+    /// The first column names the sample by file index, input path or file name,
+    /// or the barcode of a --barcode-reports report.
+    #[clap(long, value_parser)]
+    pub sample_metadata: Option<PathBuf>,
+
     /// A list of input file paths (FASTA/FASTQ) to be processed by the classify program.
     /// Supports fasta or fastq format files (e.g., .fasta, .fastq) and gzip compressed files (e.g., .fasta.gz, .fastq.gz).
+    /// Directories (searched recursively for read files) and quoted glob patterns are expanded.
     // #[clap(short = 'F', long = "files")]
     pub input_files: Vec<String>,
 }
 
 fn process_seq(
-    rows: &mut Vec<Row>,
+    minimizers: &mut Vec<(u32, u64)>,
     m_iter: &mut MinimizerIterator,
-    hash_config: &HashConfig,
-    chtable: &CHTable,
     offset: usize,
 ) -> usize {
-    let chunk_size = hash_config.hash_capacity;
-    let value_bits = hash_config.value_bits;
-    let data: Vec<(usize, u64)> = m_iter.collect();
-    for (sort, hash_key) in data {
-        let (idx, compacted) = hash_config.compact(hash_key);
-        let partition_index = idx / chunk_size;
-        let index = idx % chunk_size;
-
-        let taxid = chtable.get_from_page(index, compacted, partition_index);
-        if taxid > 0 {
-            let high = u32::combined(compacted, taxid, value_bits);
-            let row = Row::new(high, 0, sort as u32 + 1 + offset as u32);
-            rows.push(row);
-        }
+    for (sort, hash_key) in m_iter.by_ref() {
+        minimizers.push((sort as u32 + 1 + offset as u32, hash_key));
     }
     m_iter.size + offset
 }
 
+/// Looks up the minimizers of a read in the hash tables of a database
+fn lookup_rows(minimizers: &[(u32, u64)], database: &Database) -> Vec<Row> {
+    let mut rows = Vec::new();
+    for (hash_config, chtable) in &database.tables {
+        let chunk_size = hash_config.hash_capacity;
+        let value_bits = hash_config.value_bits;
+        for &(kmer_id, hash_key) in minimizers {
+            let (idx, compacted) = hash_config.compact(hash_key);
+            let partition_index = idx / chunk_size;
+            let index = idx % chunk_size;
+
+            let taxid = chtable.get_from_page(index, compacted, partition_index);
+            if taxid > 0 {
+                let high = u32::combined(compacted, taxid, value_bits);
+                rows.push(Row::new(high, 0, kmer_id));
+            }
+        }
+    }
+    if database.tables.len() > 1 {
+        let value_mask = database.hash_config().value_mask;
+        rows.sort_unstable();
+        merge_group_rows(&mut rows, value_mask, |a, b| database.taxonomy.lca(a, b));
+    }
+    rows
+}
+
+/// Hash tables and taxonomy of a loaded database
+struct Database<'a> {
+    /// One table per loaded page group, a single one for an ungrouped database
+    tables: Vec<(HashConfig, &'a CHTable)>,
+    taxonomy: &'a Taxonomy,
+}
+
+impl Database<'_> {
+    /// Hash table header of the database, the page groups share the taxonomy and
+    /// thus the value bits
+    fn hash_config(&self) -> &HashConfig {
+        &self.tables[0].0
+    }
+}
+
+/// Loads the hash tables of the database directories, see [`database_dirs`]
+fn load_tables(dirs: &[PathBuf], huge_pages: bool) -> Result<Vec<(HashConfig, CHTable)>> {
+    let mut tables = Vec::new();
+    for dir in dirs {
+        let hash_config = HashConfig::from_hash_header(dir.join("hash_config.k2d"))?;
+        println!("{:?}", hash_config);
+        if hash_config.hash_capacity == 0 {
+            panic!("`hash_capacity` can't be zero!");
+        }
+        let hash_files = find_and_sort_files(dir, "hash", ".k2d", true)?;
+        let chtable = CHTable::from_hash_files(hash_config, &hash_files, huge_pages)?;
+        tables.push((hash_config, chtable));
+    }
+    Ok(tables)
+}
+
+/// Database, settings and hooks shared by all reads of a run
+struct ClassifyContext<'a> {
+    args: &'a Args,
+    meros: Meros,
+    database: Database<'a>,
+    /// Second database of the ensemble mode
+    database2: Option<Database<'a>>,
+    /// 两个数据库的 taxid 是否可以直接比较
+    shared_taxids: bool,
+    post_processors: &'a PostProcessors,
+    report_options: ReportOptions,
+}
+
 fn process_record(
     marker: &mut Base<MinimizerIterator>,
-    args: &Args,
-    taxonomy: &Taxonomy,
-    chtable: &CHTable,
-    hash_config: &HashConfig,
+    ctx: &ClassifyContext,
     cur_taxon_counts: &TaxonCountersDash,
     classify_counter: &AtomicUsize,
-) -> String {
+) -> Option<String> {
+    let args = ctx.args;
+    let hash_config = ctx.database.hash_config();
     let id = &marker.header.id.clone();
-    let rows: Vec<Row> = marker
-        .fold(|rows, m_iter, offset| process_seq(rows, m_iter, &hash_config, chtable, offset));
+    let minimizers = marker.fold(process_seq);
 
-    let hits = HitGroup::new(rows, marker.range());
+    let hits = HitGroup::new(lookup_rows(&minimizers, &ctx.database), marker.range());
 
     let seq_len_str = marker.fmt_seq_size();
 
     let required_score = hits.required_score(args.confidence_threshold);
     let hit_data = process_hitgroup(
         &hits,
-        taxonomy,
+        ctx.database.taxonomy,
         classify_counter,
         required_score,
         args.minimum_hit_groups,
         hash_config.value_mask,
     );
 
-    hit_data.3.iter().for_each(|(key, value)| {
+    let classified = hit_data.0 == "C";
+    let mut call = ReadCall {
+        classified,
+        read_id: id.to_string(),
+        taxid: if classified {
+            hit_data.1
+        } else {
+            u64::from(args.unclassified_taxid)
+        },
+        size: seq_len_str,
+        hit_list: hit_data.2,
+        annotations: Vec::new(),
+    };
+
+    if let Some(database2) = &ctx.database2 {
+        let hits2 = HitGroup::new(lookup_rows(&minimizers, database2), marker.range());
+        let (_, call2, _, _) = process_hitgroup(
+            &hits2,
+            database2.taxonomy,
+            &AtomicUsize::new(0),
+            hits2.required_score(args.confidence_threshold),
+            args.minimum_hit_groups,
+            database2.hash_config().value_mask,
+        );
+        let agreement = compare_calls(
+            ctx.database.taxonomy,
+            hit_data.1,
+            database2.taxonomy,
+            call2,
+            ctx.shared_taxids,
+        );
+        call.annotations.push(if call2 > 0 {
+            call2.to_string()
+        } else {
+            args.unclassified_taxid.to_string()
+        });
+        call.annotations.push(agreement.as_str().to_string());
+    }
+    // 插件可以修改或丢弃这条输出, 报告按插件处理后的结果计数
+    let mut taxon_counts = hit_data.3;
+    let (kept, counted) =
+        ctx.post_processors
+            .apply_counted(&mut call, 1, ctx.database.taxonomy, &mut taxon_counts);
+    match (classified, counted) {
+        (false, true) => classify_counter.fetch_add(1, Ordering::SeqCst),
+        (true, false) => classify_counter.fetch_sub(1, Ordering::SeqCst),
+        _ => 0,
+    };
+    taxon_counts.iter().for_each(|(key, value)| {
         cur_taxon_counts
             .entry(*key)
             .or_default()
             .merge(value)
             .unwrap();
     });
-    format!(
-        "{}\t{}\t{}\t{}\t{}\n",
-        hit_data.0, id, hit_data.1, seq_len_str, hit_data.2
-    )
+    kept.then(|| call.to_line())
 }
 
 fn process_fastx_file<R>(
-    args: &Args,
-    meros: Meros,
-    hash_config: HashConfig,
+    ctx: &ClassifyContext,
     file_index: usize,
     reader: &mut R,
-    chtable: &CHTable,
-    taxonomy: &Taxonomy,
-    total_taxon_counts: &mut TaxonCounters,
-) -> io::Result<(usize, usize)>
+    metadata: Vec<(String, String)>,
+) -> io::Result<(TaxonCounters, usize, usize)>
 where
     R: Reader,
 {
+    let args = ctx.args;
     let mut writer: Box<dyn Write + Send> = match &args.output_dir {
         Some(ref file_path) => {
             let filename = file_path.join(format!("output_{}.txt", file_index));
@@ -178,21 +381,16 @@ where
     let _ = read_parallel(
         reader,
         args.num_threads,
-        &meros,
+        &ctx.meros,
         |seqs| {
             let mut buffer = String::new();
             for record in seqs {
                 seq_counter.fetch_add(1, Ordering::SeqCst);
-                let output_line = process_record(
-                    record,
-                    args,
-                    taxonomy,
-                    chtable,
-                    &hash_config,
-                    &cur_taxon_counts,
-                    &classify_counter,
-                );
-                buffer.push_str(&output_line);
+                if let Some(output_line) =
+                    process_record(record, ctx, &cur_taxon_counts, &classify_counter)
+                {
+                    buffer.push_str(&output_line);
+                }
             }
 
             buffer
@@ -214,11 +412,6 @@ where
         >,
     > = HashMap::new();
     cur_taxon_counts.iter().for_each(|entry| {
-        total_taxon_counts
-            .entry(*entry.key())
-            .or_default()
-            .merge(&entry.value())
-            .unwrap();
         sample_taxon_counts
             .entry(*entry.key())
             .or_default()
@@ -232,25 +425,154 @@ where
         let filename = output.join(format!("output_{}.kreport2", file_index));
         report_kraken_style(
             filename,
-            args.report_zero_counts,
-            args.report_kmer_data,
-            &taxonomy,
+            &ctx.report_options.with_metadata(metadata),
+            ctx.database.taxonomy,
             &sample_taxon_counts,
             thread_sequences as u64,
             (thread_sequences - thread_classified) as u64,
         )?;
     }
 
-    Ok((thread_sequences, thread_sequences - thread_classified))
+    Ok((
+        sample_taxon_counts,
+        thread_sequences,
+        thread_sequences - thread_classified,
+    ))
+}
+
+/// Reads and taxon counts of all files classified so far for one report
+#[derive(Default)]
+struct CumulativeCounts {
+    taxon_counts: TaxonCounters,
+    sequences: usize,
+    unclassified: usize,
+}
+
+impl CumulativeCounts {
+    fn add(&mut self, taxon_counts: &TaxonCounters, sequences: usize, unclassified: usize) {
+        for (taxid, counter) in taxon_counts {
+            self.taxon_counts
+                .entry(*taxid)
+                .or_default()
+                .merge(counter)
+                .unwrap();
+        }
+        self.sequences += sequences;
+        self.unclassified += unclassified;
+    }
+
+    fn write_report(
+        &self,
+        filename: PathBuf,
+        options: &ReportOptions,
+        taxonomy: &Taxonomy,
+    ) -> Result<()> {
+        // 先写临时文件再改名, 监视时读报告的程序不会读到写了一半的文件
+        let tmp_filename = filename.with_extension("kreport2.tmp");
+        report_kraken_style(
+            &tmp_filename,
+            options,
+            taxonomy,
+            &self.taxon_counts,
+            self.sequences as u64,
+            self.unclassified as u64,
+        )?;
+        std::fs::rename(tmp_filename, filename)
+    }
+}
+
+/// Read files under the watched directory that have not grown since the last scan
+///
+/// `sizes` keeps the sizes seen by the last scan, `seen` the files already classified.
+fn scan_watch_dir(
+    watch_dir: &Path,
+    seen: &HashSet<PathBuf>,
+    sizes: &mut HashMap<PathBuf, u64>,
+) -> Result<Vec<PathBuf>> {
+    let files = match expand_input_files(&[watch_dir.to_path_buf()]) {
+        Ok((files, _)) => files,
+        Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    let mut ready = Vec::new();
+    for file in files {
+        if seen.contains(&file) {
+            continue;
+        }
+        let size = match std::fs::metadata(&file) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => continue,
+        };
+        if sizes.insert(file.clone(), size) == Some(size) {
+            sizes.remove(&file);
+            ready.push(file);
+        }
+    }
+    Ok(ready)
+}
+
+/// Sleeps for `duration`, returns early and false when a termination signal arrives
+fn sleep_unless_interrupted(duration: Duration) -> bool {
+    let wake = Instant::now() + duration;
+    while interrupted().is_none() {
+        let now = Instant::now();
+        if now >= wake {
+            return true;
+        }
+        std::thread::sleep((wake - now).min(Duration::from_millis(200)));
+    }
+    false
+}
+
+fn report_options(args: &Args, taxonomy: &Taxonomy) -> Result<ReportOptions> {
+    let clade_minimizers = if args.minimizer_fraction {
+        let filename = args
+            .taxon_minimizers
+            .clone()
+            .unwrap_or_else(|| args.database.join(TAXON_MINIMIZERS_FILENAME));
+        Some(Arc::new(load_clade_minimizers(filename, taxonomy)?))
+    } else {
+        None
+    };
+    Ok(ReportOptions {
+        precision: args.report_precision as usize,
+        tab_only: args.tab_only,
+        unclassified_taxid: args.unclassified_taxid,
+        unclassified_under_root: args.unclassified_under_root,
+        clade_minimizers,
+        ..ReportOptions::new(args.report_zero_counts, args.report_kmer_data)
+    })
 }
 
 fn process_files(
     args: Args,
     meros: Meros,
-    hash_config: HashConfig,
-    chtable: &CHTable,
-    taxonomy: &Taxonomy,
+    database: Database,
+    database2: Option<Database>,
+    post_processors: &PostProcessors,
+    report_options: ReportOptions,
 ) -> Result<()> {
+    // 文件编号需要同时适配两个数据库的 value_bits
+    let value_bits = database2
+        .as_ref()
+        .map_or(database.hash_config().value_bits, |db2| {
+            db2.hash_config()
+                .value_bits
+                .min(database.hash_config().value_bits)
+        });
+    let taxonomy = database.taxonomy;
+    let shared_taxids = database2
+        .as_ref()
+        .is_some_and(|db2| taxonomy.shares_taxids(db2.taxonomy));
+    let ctx = ClassifyContext {
+        args: &args,
+        meros,
+        database,
+        database2,
+        shared_taxids,
+        post_processors,
+        report_options,
+    };
     let (mut file_index, mut file_writer) = if let Some(out_dir) = &args.output_dir {
         let file_path = out_dir.join("sample_file.map");
         let file_writer = create_sample_file(&file_path);
@@ -266,68 +588,222 @@ fn process_files(
         )
     };
 
-    let mut process_funcs = |files: Vec<&[String]>| -> Result<()> {
-        let file_bits = (((files.len() + file_index) as f64).log2().ceil() as usize).max(1);
-        if file_bits > hash_config.value_bits {
-            panic!("The number of files is too large to process.");
+    let files: Vec<&[String]> = if args.paired_end_processing && !args.single_file_pairs {
+        // 处理成对的文件
+        args.input_files.chunks(2).collect()
+    } else {
+        args.input_files.chunks(1).collect()
+    };
+    let file_bits = (((files.len() + file_index) as f64).log2().ceil() as usize).max(1);
+    if file_bits > value_bits {
+        panic!("The number of files is too large to process.");
+    }
+
+    let barcode_regex = if args.barcode_reports {
+        Some(Regex::new(&args.barcode_regex).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?)
+    } else {
+        None
+    };
+    let sample_metadata = match &args.sample_metadata {
+        Some(sheet) => Some(SampleMetadata::from_file(sheet)?),
+        None => None,
+    };
+    let options = ctx.report_options.clone();
+    let mut total = CumulativeCounts::default();
+    let mut barcodes: HashMap<String, CumulativeCounts> = HashMap::new();
+    let mut sample_summaries = Vec::new();
+
+    let mut classify_file = |file_pair: &[String]| -> Result<(TaxonCounters, usize, usize)> {
+        file_index += 1;
+        let file_bits = ((file_index as f64).log2().ceil() as usize).max(1);
+        if file_bits > value_bits {
+            return Err(Error::other("The number of files is too large to process."));
         }
 
-        let mut total_taxon_counts = TaxonCounters::new();
-        let mut total_seqs: usize = 0;
-        let mut total_unclassified: usize = 0;
-        for file_pair in files {
-            file_index += 1;
-
-            writeln!(file_writer, "{}\t{}", file_index, file_pair.join(","))?;
-            file_writer.flush().unwrap();
-
-            let score = args.minimum_quality_score;
-            let paths = OptionPair::from_slice(file_pair);
-            let mut reader = FastxReader::from_paths(paths, file_index, score)?;
-            // let mut reader = create_reader(file_pair, file_index, score)?;
-            let (thread_sequences, thread_unclassified) = process_fastx_file(
-                &args,
-                meros,
-                hash_config,
-                file_index,
-                &mut reader,
-                chtable,
-                taxonomy,
-                &mut total_taxon_counts,
-            )?;
-            total_seqs += thread_sequences;
-            total_unclassified += thread_unclassified;
+        writeln!(file_writer, "{}\t{}", file_index, file_pair.join(","))?;
+        file_writer.flush().unwrap();
+
+        let score = args.minimum_quality_score;
+        let paths = OptionPair::from_slice(file_pair);
+        let mut reader = Interruptible::new(FastxReader::from_paths(paths, file_index, score)?);
+        // let mut reader = create_reader(file_pair, file_index, score)?;
+        let metadata = sample_metadata
+            .as_ref()
+            .map(|sheet| sheet.lookup(file_index, file_pair))
+            .unwrap_or_default();
+        let counts = process_fastx_file(&ctx, file_index, &mut reader, metadata.clone())?;
+        ctx.post_processors.tally_remapped("direct", file_index);
+        // 读取出错时 read_parallel 直接结束, 剩下的 read 没有分类
+        if let Some(error) = reader.error() {
+            warn(
+                "direct",
+                "truncated_reads",
+                Some(file_index),
+                &format!(
+                    "sample {} ends at a read error, the reads after it are skipped: {}",
+                    file_index, error
+                ),
+            );
         }
-        if let Some(output) = &args.output_dir {
-            let filename = output.join("output.kreport2");
-            report_kraken_style(
-                filename,
-                args.report_zero_counts,
-                args.report_kmer_data,
-                &taxonomy,
-                &total_taxon_counts,
-                total_seqs as u64,
-                total_unclassified as u64,
-            )?;
+        if sample_metadata.is_some() {
+            let (_, sequences, unclassified) = &counts;
+            sample_summaries.push(json!({
+                "index": file_index,
+                "files": file_pair,
+                "total_sequences": sequences,
+                "classified": sequences - unclassified,
+                "unclassified": unclassified,
+                "metadata": metadata
+                    .into_iter()
+                    .map(|(key, value)| (key, value.into()))
+                    .collect::<serde_json::Map<_, _>>(),
+            }));
+        }
+        Ok(counts)
+    };
+    // 累加到总报告和所属 barcode 的报告, barcode 报告 (监视时还有总报告) 每个文件后重写
+    let mut add_counts = |file_pair: &[String], counts: (TaxonCounters, usize, usize)| {
+        let (taxon_counts, sequences, unclassified) = counts;
+        total.add(&taxon_counts, sequences, unclassified);
+        if let (Some(_), Some(output)) = (&args.watch, &args.output_dir) {
+            total.write_report(output.join("output.kreport2"), &options, taxonomy)?;
+        }
+        let barcode = barcode_regex
+            .as_ref()
+            .and_then(|regex| barcode_of(Path::new(&file_pair[0]), regex));
+        match (barcode, &args.output_dir) {
+            (Some(barcode), Some(output)) => {
+                let counts = barcodes.entry(barcode.clone()).or_default();
+                counts.add(&taxon_counts, sequences, unclassified);
+                let filename = output.join(format!("output_{}.kreport2", barcode));
+                let metadata = sample_metadata
+                    .as_ref()
+                    .map(|sheet| sheet.lookup_name(&barcode))
+                    .unwrap_or_default();
+                counts.write_report(filename, &options.with_metadata(metadata), taxonomy)
+            }
+            _ => Ok(()),
         }
-
-        Ok(())
     };
 
-    if args.paired_end_processing && !args.single_file_pairs {
-        // 处理成对的文件
-        let files = args.input_files.chunks(2).collect();
-        process_funcs(files)?;
-    } else {
-        let files = args.input_files.chunks(1).collect();
-        process_funcs(files)?;
+    for file_pair in files {
+        let counts = classify_file(file_pair)?;
+        add_counts(file_pair, counts)?;
+        if interrupted().is_some() {
+            break;
+        }
+    }
+
+    // 监视目录时, 空闲中收到终止信号是正常结束
+    let mut stopped_idle = false;
+    if let (Some(watch_dir), None) = (&args.watch, interrupted()) {
+        let mut seen: HashSet<PathBuf> = args.input_files.iter().map(PathBuf::from).collect();
+        let mut sizes = HashMap::new();
+        let timeout = Duration::from_secs(args.watch_timeout);
+        let mut last_file = Instant::now();
+        println!("watching {:?} for new read files...", watch_dir);
+        loop {
+            for file in scan_watch_dir(watch_dir, &seen, &mut sizes)? {
+                let file_pair = [file.to_string_lossy().into_owned()];
+                println!("classify {}", file_pair[0]);
+                let counts = classify_file(&file_pair)?;
+                add_counts(&file_pair, counts)?;
+                seen.insert(file);
+                last_file = Instant::now();
+                if interrupted().is_some() {
+                    break;
+                }
+            }
+            if interrupted().is_some() {
+                break;
+            }
+            if args.watch_timeout > 0 && last_file.elapsed() >= timeout {
+                println!("no new read files for {:?}, stop watching", timeout);
+                break;
+            }
+            if !sleep_unless_interrupted(Duration::from_secs(args.watch_interval)) {
+                stopped_idle = true;
+                break;
+            }
+        }
+    }
+    let incomplete = interrupted().is_some() && !stopped_idle;
+
+    if let Some(output) = &args.output_dir {
+        total.write_report(output.join("output.kreport2"), &options, taxonomy)?;
+        if sample_metadata.is_some() {
+            let writer = BufWriter::new(File::create(output.join("samples.json"))?);
+            serde_json::to_writer_pretty(writer, &sample_summaries)?;
+        }
+        // 中断时报告只包含已读取的序列
+        if incomplete {
+            write_incomplete_marker(output, "direct", &format!("last_sample\t{}", file_index))?;
+        } else {
+            clear_incomplete_marker(output);
+        }
+        if let Some(filename) = write_findings(output)? {
+            println!("findings written to {}", filename.display());
+        }
+    }
+    file_writer.flush()?;
+    if incomplete {
+        return Err(interrupted_error("direct"));
     }
 
     Ok(())
 }
 
-pub fn run(args: Args) -> Result<()> {
-    let options_filename = &args.database.join("opts.k2d");
+/// Warns about options that have no effect with the other options given
+fn check_parameters(args: &Args) {
+    let mismatch = |message: &str| warn("direct", "parameter_mismatch", None, message);
+    if args.watch.is_none()
+        && (args.watch_interval != DEFAULT_WATCH_INTERVAL
+            || args.watch_timeout != DEFAULT_WATCH_TIMEOUT)
+    {
+        mismatch("--watch-interval and --watch-timeout have no effect without --watch");
+    }
+    if !args.barcode_reports && args.barcode_regex != DEFAULT_BARCODE_REGEX {
+        mismatch("--barcode-regex has no effect without --barcode-reports");
+    }
+    if args.taxon_minimizers.is_some() && !args.minimizer_fraction {
+        mismatch("--taxon-minimizers has no effect without --minimizer-fraction");
+    }
+}
+
+/// Whether two databases extract the same minimizers from a read
+fn same_minimizer_options(a: &IndexOptions, b: &IndexOptions) -> bool {
+    a.k == b.k
+        && a.l == b.l
+        && a.spaced_seed_mask == b.spaced_seed_mask
+        && a.toggle_mask == b.toggle_mask
+        && a.dna_db == b.dna_db
+        && a.minimum_acceptable_hash_value == b.minimum_acceptable_hash_value
+        && a.revcom_version == b.revcom_version
+}
+
+pub fn run(mut args: Args) -> Result<()> {
+    let inputs: Vec<PathBuf> = args.input_files.iter().map(PathBuf::from).collect();
+    let (mut input_files, expanded) = expand_input_files(&inputs)?;
+    if args.paired_end_processing
+        && !args.single_file_pairs
+        && (expanded || args.pair_regex.is_some())
+    {
+        let pair_regex = args.pair_regex.as_deref().unwrap_or(DEFAULT_PAIR_REGEX);
+        input_files = pair_read_files(&input_files, pair_regex)?;
+    }
+    args.input_files = input_files
+        .iter()
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect();
+
+    if args.audit {
+        enable_audit();
+    }
+    check_parameters(&args);
+
+    // 分组数据库的选项和哈希表在各组目录下
+    let group_dirs = database_dirs(&args.database, &args.page_groups)?;
+    let options_filename = &group_dirs[0].join("opts.k2d");
     let idx_opts = IndexOptions::read_index_options(options_filename)?;
 
     if args.paired_end_processing && !args.single_file_pairs && args.input_files.len() % 2 != 0 {
@@ -337,25 +813,69 @@ pub fn run(args: Args) -> Result<()> {
             "Paired-end processing requires an even number of input files.",
         ));
     }
+    if args.watch.is_some() && args.paired_end_processing && !args.single_file_pairs {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Watched files are classified one by one, paired files need -S.",
+        ));
+    }
 
     let taxonomy_filename = args.database.join("taxo.k2d");
     let taxo = Taxonomy::from_file(taxonomy_filename)?;
+    // 参考 minimizer 表在加载哈希表之前检查
+    let options = report_options(&args, &taxo)?;
 
-    let hash_config = HashConfig::from_hash_header(&args.database.join("hash_config.k2d"))?;
-
-    println!("{:?}", hash_config);
-    if hash_config.hash_capacity == 0 {
-        panic!("`hash_capacity` can't be zero!");
-    }
     println!("classify start...");
     let start = Instant::now();
     let meros = idx_opts.as_meros();
-    let hash_files = find_and_sort_files(&args.database, "hash", ".k2d", true)?;
-    let chtable = CHTable::from_hash_files(hash_config, &hash_files)?;
+    let tables = load_tables(&group_dirs, args.huge_pages)?;
+    let request_huge_pages = args.huge_pages;
+
+    let database = Database {
+        tables: tables
+            .iter()
+            .map(|(hash_config, chtable)| (*hash_config, chtable))
+            .collect(),
+        taxonomy: &taxo,
+    };
 
-    process_files(args, meros, hash_config, &chtable, &taxo)?;
+    let mut second = None;
+    if let Some(database2) = &args.database2 {
+        let group_dirs2 = database_dirs(database2, &[])?;
+        let idx_opts2 = IndexOptions::read_index_options(group_dirs2[0].join("opts.k2d"))?;
+        // 两个数据库必须使用相同的 minimizer 参数，才能共用一次扫描结果
+        if !same_minimizer_options(&idx_opts, &idx_opts2) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{:?} and {:?} were built with different k-mer/minimizer options",
+                    args.database, database2
+                ),
+            ));
+        }
+        let taxo2 = Taxonomy::from_file(database2.join("taxo.k2d"))?;
+        let tables2 = load_tables(&group_dirs2, args.huge_pages)?;
+        second = Some((tables2, taxo2));
+    }
+    let database2 = second.as_ref().map(|(tables, taxonomy)| Database {
+        tables: tables
+            .iter()
+            .map(|(hash_config, chtable)| (*hash_config, chtable))
+            .collect(),
+        taxonomy,
+    });
+
+    let post_processors = PostProcessors::from_libraries(&args.plugins)?;
+    process_files(args, meros, database, database2, &post_processors, options)?;
     let duration = start.elapsed();
     println!("classify took: {:?}", duration);
+    if request_huge_pages {
+        // 以内核实际分配的大页为准, madvise 成功并不代表页面已经使用了大页
+        match anon_huge_pages_kb() {
+            Some(kb) if kb > 0 => println!("huge pages: {} kB backed by huge pages", kb),
+            _ => println!("huge pages: unavailable, fell back to regular pages"),
+        }
+    }
     Ok(())
 }
 
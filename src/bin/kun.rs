@@ -1,18 +1,35 @@
 use clap::{Parser, Subcommand};
 mod annotate;
 mod build_k2_db;
+mod check_report;
 mod chunk_db;
+mod compare_reports;
 mod direct;
 mod estimate_capacity;
 mod hashshard;
 mod merge_fna;
+mod migrate;
 mod resolve;
 // mod seqid2taxid;
 mod splitr;
 
+use kun_peng::abundance::{parse_spike_ins, SPIKE_IN_COLUMN};
 use kun_peng::args::ClassifyArgs;
 use kun_peng::args::{parse_size, Build};
-use kun_peng::utils::find_files;
+use kun_peng::audit::{enable_audit, with_findings, write_findings};
+use kun_peng::coverage::{
+    read_taxon_minimizers, write_taxon_minimizers, TAXON_MINIMIZERS_FILENAME,
+};
+use kun_peng::interrupt::{
+    install_signal_handlers, interrupted, write_incomplete_marker, Checkpoint, CHECKPOINT_FILE,
+};
+use kun_peng::metadata::SampleMetadata;
+use kun_peng::page_group::{database_dirs, split_library, write_page_groups};
+use kun_peng::plugin::PostProcessors;
+use kun_peng::taxonomy::Taxonomy;
+use kun_peng::utils::{find_files, read_id_to_taxon_map};
+use std::collections::HashMap;
+use std::fs::{create_dir_all, remove_dir_all, remove_file};
 // use std::io::Result;
 use std::path::PathBuf;
 use std::time::Instant;
@@ -56,6 +73,11 @@ struct BuildArgs {
     /// library fna temp file max size
     #[arg(long = "max-file-size", value_parser = parse_size, default_value = "2G")]
     pub max_file_size: usize,
+
+    /// Split the index into one page group per clade at this rank (e.g. superkingdom or phylum).
+    /// Each group is built into $db/groups/<taxid>, classify --page-groups loads a subset of them
+    #[arg(long)]
+    pub page_group_rank: Option<String>,
 }
 
 #[derive(Parser, Debug)]
@@ -74,6 +96,12 @@ impl From<ClassifyArgs> for splitr::Args {
             minimum_quality_score: item.minimum_quality_score,
             num_threads: item.num_threads,
             chunk_dir: item.chunk_dir,
+            pair_regex: item.pair_regex,
+            disk_check: item.disk_check,
+            dedup: item.dedup,
+            dedup_max_reads: item.dedup_max_reads,
+            hits_only: false,
+            audit: false,
             input_files: item.input_files,
         }
     }
@@ -87,6 +115,13 @@ impl From<ClassifyArgs> for annotate::Args {
             batch_size: item.batch_size,
             buffer_size: item.buffer_size,
             num_threads: item.num_threads,
+            huge_pages: item.huge_pages,
+            page_cache_size: item.page_cache_size,
+            eviction_policy: item.eviction_policy,
+            split_threshold: item.split_threshold,
+            writer_buffer_size: item.writer_buffer_size,
+            flush_interval: item.flush_interval,
+            resume: false,
         }
     }
 }
@@ -102,6 +137,24 @@ impl From<ClassifyArgs> for resolve::Args {
             output_dir: item.output_dir,
             report_kmer_data: item.report_kmer_data,
             report_zero_counts: item.report_zero_counts,
+            report_precision: item.report_precision,
+            tab_only: item.tab_only,
+            unclassified_taxid: item.unclassified_taxid,
+            unclassified_under_root: item.unclassified_under_root,
+            plugins: item.plugins,
+            writer_buffer_size: item.writer_buffer_size,
+            flush_interval: item.flush_interval,
+            sample_metadata: item.sample_metadata,
+            heatmap_bins: item.heatmap_bins,
+            heatmap_format: item.heatmap_format,
+            abundance_rank: item.abundance_rank,
+            abundance_normalization: item.abundance_normalization,
+            genome_sizes: item.genome_sizes,
+            contaminant_fold: item.contaminant_fold,
+            subtract_controls: item.subtract_controls,
+            minimizer_fraction: item.minimizer_fraction,
+            taxon_minimizers: item.taxon_minimizers,
+            audit: item.audit,
         }
     }
 }
@@ -134,10 +187,49 @@ impl From<BuildArgs> for merge_fna::Args {
             download_dir: item.download_dir,
             database: item.build.database,
             max_file_size: item.max_file_size,
+            audit: item.build.audit,
         }
     }
 }
 
+/// Builds one database per clade at `rank` into the page group directories
+fn build_page_groups(args: &BuildArgs, rank: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let database = &args.build.database;
+    let taxonomy = Taxonomy::from_file(database.join("taxo.k2d"))?;
+    if !(1..taxonomy.node_count() as u32).any(|taxid| taxonomy.rank_of(taxid) == rank) {
+        return Err(format!("no taxon of rank {} in the taxonomy", rank).into());
+    }
+    let id_to_taxon_map = read_id_to_taxon_map(database.join("seqid2taxid.map"))?;
+    let groups = split_library(database, &taxonomy, &id_to_taxon_map, rank)?;
+
+    // 各组的参考 minimizer 表合并到数据库目录, 供 resolve 使用;
+    // 基因组大小表由 merge-fna 直接写在数据库目录
+    let mut taxon_minimizers: HashMap<u64, u64> = HashMap::new();
+    for group in &groups {
+        println!(
+            "build page group {} ({}): {} sequences",
+            group.taxid, group.name, group.sequences
+        );
+        let group_dir = group.dir(database);
+        let mut group_args = args.clone();
+        group_args.build.database = group_dir.clone();
+        let ec_args = estimate_capacity::Args::from(group_args.clone());
+        let required_capacity = estimate_capacity::run(ec_args);
+        chunk_db::run(chunk_db::Args::from(group_args), required_capacity)?;
+        build_k2_db::run(&group_dir)?;
+        // 组的 library 是数据库 library 的拷贝, 建完即删
+        remove_dir_all(group_dir.join("library"))?;
+
+        for (taxid, count) in read_taxon_minimizers(group_dir.join(TAXON_MINIMIZERS_FILENAME))? {
+            *taxon_minimizers.entry(taxid).or_insert(0) += count;
+        }
+    }
+    write_taxon_minimizers(database.join(TAXON_MINIMIZERS_FILENAME), &taxon_minimizers)?;
+    write_page_groups(database, &groups)?;
+    println!("{} page groups at rank {}", groups.len(), rank);
+    Ok(())
+}
+
 #[derive(Subcommand, Debug)]
 enum Commands {
     Estimate(estimate_capacity::Args),
@@ -150,69 +242,243 @@ enum Commands {
     Classify(ClassifyArgs),
     Direct(direct::Args),
     MergeFna(merge_fna::Args),
+    Migrate(migrate::Args),
+    CompareReports(compare_reports::Args),
+    CheckReport(check_report::Args),
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
 
+    // 只有分类的几个阶段能在收到信号后收尾, 其他命令保持默认的信号处理
     match args.cmd {
         Commands::MergeFna(cmd_args) => {
-            merge_fna::run(cmd_args)?;
+            if cmd_args.audit {
+                enable_audit();
+            }
+            let database = cmd_args.database.clone();
+            with_findings(database, || merge_fna::run(cmd_args))?;
         }
         Commands::Estimate(cmd_args) => {
             estimate_capacity::run(cmd_args);
         }
         Commands::Build(cmd_args) => {
-            let fna_args = merge_fna::Args::from(cmd_args.clone());
-            merge_fna::run(fna_args)?;
-            let ec_args = estimate_capacity::Args::from(cmd_args.clone());
-            let required_capacity = estimate_capacity::run(ec_args);
+            if cmd_args.build.audit {
+                enable_audit();
+            }
+            // 建库失败时也写入已经收集的 findings
+            let database = cmd_args.build.database.clone();
+            with_findings(database, || -> Result<(), Box<dyn std::error::Error>> {
+                let fna_args = merge_fna::Args::from(cmd_args.clone());
+                merge_fna::run(fna_args)?;
+                if let Some(rank) = &cmd_args.page_group_rank {
+                    return build_page_groups(&cmd_args, rank);
+                }
+                let ec_args = estimate_capacity::Args::from(cmd_args.clone());
+                let required_capacity = estimate_capacity::run(ec_args);
 
-            let build_args = chunk_db::Args::from(cmd_args.clone());
-            let database = &build_args.build.database.clone();
-            chunk_db::run(build_args, required_capacity)?;
-            build_k2_db::run(database)?;
+                let build_args = chunk_db::Args::from(cmd_args.clone());
+                let database = &build_args.build.database.clone();
+                chunk_db::run(build_args, required_capacity)?;
+                build_k2_db::run(database)?;
+                Ok(())
+            })?;
         }
         Commands::Hashshard(cmd_args) => {
             hashshard::run(cmd_args)?;
         }
         Commands::Splitr(cmd_args) => {
+            install_signal_handlers();
             splitr::run(cmd_args)?;
         }
         Commands::Annotate(cmd_args) => {
+            install_signal_handlers();
             annotate::run(cmd_args)?;
         }
         Commands::Resolve(cmd_args) => {
+            install_signal_handlers();
             resolve::run(cmd_args)?;
         }
         Commands::Classify(cmd_args) => {
+            install_signal_handlers();
             let start = Instant::now();
 
             let splitr_args = splitr::Args::from(cmd_args.clone());
-            let chunk_files = find_files(&splitr_args.chunk_dir, "sample", ".k2");
-            let sample_files = find_files(&splitr_args.chunk_dir, "sample_id", ".map");
-            let bin_files = find_files(&splitr_args.chunk_dir, "sample", ".bin");
-            if !chunk_files.is_empty() || !sample_files.is_empty() || !bin_files.is_empty() {
+            let chunk_dir = splitr_args.chunk_dir.clone();
+            let resume = if cmd_args.resume {
+                match Checkpoint::read_from_dir(&chunk_dir)? {
+                    Some(checkpoint) => Some(checkpoint),
+                    None => {
+                        return Err(format!(
+                            "no {} in {} to resume from",
+                            CHECKPOINT_FILE,
+                            chunk_dir.display()
+                        )
+                        .into())
+                    }
+                }
+            } else {
+                None
+            };
+            let chunk_files = find_files(&chunk_dir, "sample", ".k2");
+            let sample_files = find_files(&chunk_dir, "sample_id", ".map");
+            let bin_files = find_files(&chunk_dir, "sample", ".bin");
+            if resume.is_none()
+                && (!chunk_files.is_empty() || !sample_files.is_empty() || !bin_files.is_empty())
+            {
+                if let Ok(Some(checkpoint)) = Checkpoint::read_from_dir(&chunk_dir) {
+                    eprintln!(
+                        "a previous run was interrupted during {} (signal {}), pass --resume to continue it",
+                        checkpoint.stage, checkpoint.signal
+                    );
+                }
                 return Err(Box::new(std::io::Error::new(
                     std::io::ErrorKind::Other,
                     format!(
                         "The directory '{}' must not contain files with extensions '.k2', '.map', or '.bin' for 'sample' and 'sample_id'",
-                        &splitr_args.chunk_dir.display()
+                        &chunk_dir.display()
                     ),
                 )));
             }
-            splitr::run(splitr_args)?;
-            let annotate_args = annotate::Args::from(cmd_args.clone());
-            annotate::run(annotate_args)?;
+            let output_dir = cmd_args.output_dir.clone();
+            // 收到终止信号时写入 checkpoint 和 INCOMPLETE 标记
+            let checkpoint =
+                |stage: &str, group: usize, completed: &[&str], pending: Vec<PathBuf>| {
+                    if interrupted().is_none() {
+                        return;
+                    }
+                    let checkpoint = Checkpoint {
+                        group,
+                        ..Checkpoint::new(stage, completed, pending)
+                    };
+                    match checkpoint.write_to_dir(&chunk_dir) {
+                        Ok(filename) => eprintln!("checkpoint written to {}", filename.display()),
+                        Err(e) => eprintln!("failed to write checkpoint: {}", e),
+                    }
+                    if let Some(output) = &output_dir {
+                        if stage != "resolve" && output.is_dir() {
+                            let _ = write_incomplete_marker(output, stage, "");
+                        }
+                    }
+                };
+
+            // splitr 的警告也要记录, resolve 结束时写入 findings.json
+            if cmd_args.audit {
+                enable_audit();
+            }
+            // 某个阶段失败时也写入已经收集的 findings
+            let save_findings = || {
+                if let Some(output) = output_dir.as_ref().filter(|_| cmd_args.audit) {
+                    let result = create_dir_all(output).and_then(|_| write_findings(output));
+                    match result {
+                        Ok(Some(filename)) => {
+                            eprintln!("findings written to {}", filename.display())
+                        }
+                        Ok(None) => {}
+                        Err(e) => eprintln!("failed to write findings: {}", e),
+                    }
+                }
+            };
+            // 在运行 splitr 之前检查插件能否加载
+            PostProcessors::from_libraries(&cmd_args.plugins)?;
+            // 丰度表的 rank, 基因组大小表和参考 minimizer 表也先检查
+            if let Some(rank) = &cmd_args.abundance_rank {
+                let taxonomy = Taxonomy::from_file(cmd_args.database.join("taxo.k2d"))?;
+                let resolve_args = resolve::Args::from(cmd_args.clone());
+                resolve::load_abundance_options(&resolve_args, &taxonomy, rank)?;
+            }
+            if cmd_args.minimizer_fraction {
+                let taxonomy = Taxonomy::from_file(cmd_args.database.join("taxo.k2d"))?;
+                let resolve_args = resolve::Args::from(cmd_args.clone());
+                resolve::load_clade_minimizers(&resolve_args, &taxonomy)?;
+            }
+            if let Some(sheet) = &cmd_args.sample_metadata {
+                let sheet = SampleMetadata::from_file(sheet)?;
+                for spike_ins in sheet.values(SPIKE_IN_COLUMN) {
+                    parse_spike_ins(spike_ins)?;
+                }
+            }
+            // 分组数据库依次用每个选中的组跑 splitr 和 annotate, 命中追加到同一批 bin 文件,
+            // 之后的组只写 chunk 文件, 样本映射只在第一个组写一次
+            let group_dirs = database_dirs(&cmd_args.database, &cmd_args.page_groups)?;
+            // --resume 从中断的组和阶段继续, 之前完成的组不再处理
+            let (first_group, first_stage) = match &resume {
+                Some(checkpoint) if checkpoint.stage == "resolve" => (group_dirs.len(), "resolve"),
+                Some(checkpoint) if checkpoint.group < group_dirs.len() => {
+                    (checkpoint.group, checkpoint.stage.as_str())
+                }
+                Some(checkpoint) => {
+                    return Err(format!(
+                        "the checkpoint is for page group {} of {}, pass the same --page-groups",
+                        checkpoint.group + 1,
+                        group_dirs.len()
+                    )
+                    .into())
+                }
+                None => (0, "splitr"),
+            };
+            if resume.is_some() {
+                match first_stage {
+                    "resolve" => println!("resume from resolve"),
+                    stage => println!("resume from {} of page group {}", stage, first_group + 1),
+                }
+            }
+            if resume.is_some() && first_stage == "splitr" {
+                // 中断的 splitr 只写了部分 chunk 文件, 删掉重做; 第一个组还要重写样本映射
+                let mut partial = find_files(&chunk_dir, "sample", ".k2");
+                if first_group == 0 {
+                    partial.extend(find_files(&chunk_dir, "sample", ".map"));
+                }
+                for filename in partial {
+                    remove_file(filename)?;
+                }
+            }
+            for (i, group_dir) in group_dirs.iter().enumerate().skip(first_group) {
+                // annotate 中断时 splitr 已经完成, 接着处理剩下的 chunk 文件
+                let resume_annotate = i == first_group && first_stage == "annotate";
+                if !resume_annotate {
+                    let mut splitr_args = splitr_args.clone();
+                    splitr_args.database = group_dir.clone();
+                    splitr_args.hits_only = i > 0;
+                    let input_files = splitr_args.input_files.clone();
+                    splitr::run(splitr_args).inspect_err(|_| {
+                        checkpoint("splitr", i, &[], input_files);
+                        save_findings();
+                    })?;
+                }
+                let mut annotate_args = annotate::Args::from(cmd_args.clone());
+                annotate_args.database = group_dir.clone();
+                annotate_args.resume = resume_annotate;
+                annotate::run(annotate_args).inspect_err(|_| {
+                    let pending = find_files(&chunk_dir, "sample", ".k2");
+                    checkpoint("annotate", i, &["splitr"], pending);
+                    save_findings();
+                })?;
+            }
             let resolve_args = resolve::Args::from(cmd_args.clone());
-            resolve::run(resolve_args)?;
+            resolve::run(resolve_args).inspect_err(|_| {
+                let pending = find_files(&chunk_dir, "sample_id", ".map");
+                checkpoint("resolve", 0, &["splitr", "annotate"], pending);
+                save_findings();
+            })?;
+            let _ = std::fs::remove_file(chunk_dir.join(CHECKPOINT_FILE));
 
             let duration = start.elapsed();
             println!("Classify took: {:?}", duration);
         }
         Commands::Direct(cmd_args) => {
+            install_signal_handlers();
             direct::run(cmd_args)?;
         }
+        Commands::Migrate(cmd_args) => {
+            migrate::run(cmd_args)?;
+        }
+        Commands::CompareReports(cmd_args) => {
+            compare_reports::run(cmd_args)?;
+        }
+        Commands::CheckReport(cmd_args) => {
+            check_report::run(cmd_args)?;
+        }
     }
 
     Ok(())
@@ -1,6 +1,11 @@
 use clap::Parser;
 use flate2::read::GzDecoder;
+use kun_peng::abundance::{
+    median_genome_sizes, write_assembly_sizes, write_genome_sizes, ASSEMBLY_SIZES_FILENAME,
+    GENOME_SIZES_FILENAME,
+};
 use kun_peng::args::parse_size;
+use kun_peng::audit::{enable_audit, warn, with_findings};
 use kun_peng::db::generate_taxonomy;
 use kun_peng::utils::{find_files, open_file, read_id_to_taxon_map};
 use rayon::prelude::*;
@@ -26,6 +31,11 @@ pub struct Args {
     /// library fna temp file max size
     #[arg(long = "max-file-size", value_parser = parse_size, default_value = "2G")]
     pub max_file_size: usize,
+
+    /// Audit mode: collect data-quality warnings, like malformed assembly summary lines,
+    /// into findings.json in the database directory
+    #[clap(long, action)]
+    pub audit: bool,
 }
 
 struct SizedWriter {
@@ -108,7 +118,18 @@ fn parse_assembly_fna(assembly_file: &PathBuf, site: &str) -> Result<Vec<(String
         }
 
         let fields: Vec<&str> = line.split('\t').collect();
-        if fields.len() > 19 {
+        if fields.len() <= 19 {
+            warn(
+                "merge_fna",
+                "malformed_record",
+                None,
+                &format!(
+                    "skip assembly summary line with {} fields in {:?}",
+                    fields.len(),
+                    assembly_file
+                ),
+            );
+        } else {
             let (taxid, _, ftp_path) = (fields[5], fields[11], fields[19]);
 
             if ftp_path == "na" {
@@ -138,7 +159,7 @@ fn process_gz_file(
     fna_writer: &mut SizedWriter,
     fna_start: &regex::Regex,
     taxid: &str,
-) -> Result<()> {
+) -> Result<u64> {
     let file = open_file(gz_file)?;
     let decompressor = GzDecoder::new(BufReader::new(file));
     let mut reader = BufReader::new(decompressor);
@@ -146,6 +167,7 @@ fn process_gz_file(
     let mut line = String::new();
     let mut map_buffer = String::new(); // Buffer for map writer
     let mut fna_buffer = String::new(); // Buffer for fna writer
+    let mut bases = 0u64; // 整个 assembly 的碱基数
 
     while reader.read_line(&mut line)? != 0 {
         if let Some(caps) = fna_start.captures(&line) {
@@ -159,6 +181,7 @@ fn process_gz_file(
 
             fna_buffer.push_str(&format!(">taxid|{}|{}", taxid, &line[1..]));
         } else {
+            bases += line.trim_end().len() as u64;
             fna_buffer.push_str(&line);
         }
 
@@ -188,7 +211,7 @@ fn process_gz_file(
     fna_writer.flush()?;
     map_writer.flush()?;
 
-    Ok(())
+    Ok(bases)
 }
 
 const PREFIX: &'static str = "assembly_summary";
@@ -206,6 +229,8 @@ fn merge_fna_parallel(
     let fna_start: regex::Regex = regex::Regex::new(r"^>(\S+)").unwrap();
     let is_empty = AtomicBool::new(true);
     let writers: Arc<Mutex<HashMap<usize, SizedWriter>>> = Arc::new(Mutex::new(HashMap::new()));
+    // 每个 assembly 的 (taxid, 名称, 碱基数)
+    let assembly_sizes: Mutex<Vec<(u64, String, u64)>> = Mutex::new(Vec::new());
 
     for assembly_file in assembly_files {
         if let Some(caps) = file_site.captures(assembly_file.to_string_lossy().as_ref()) {
@@ -235,18 +260,36 @@ fn merge_fna_parallel(
                             .unwrap(),
                     );
 
-                    if let Err(e) = process_gz_file(
+                    match process_gz_file(
                         &gz_file,
                         &mut map_writer,
                         &mut fna_writer,
                         &fna_start,
                         &taxid,
                     ) {
-                        eprintln!("process_gz_file error: {}", e);
-                    } else {
-                        fna_writer.flush().unwrap();
-                        map_writer.flush().unwrap();
-                        is_empty.fetch_and(false, Ordering::Relaxed);
+                        Err(e) => warn(
+                            "merge_fna",
+                            "assembly_error",
+                            None,
+                            &format!("skip {:?}: {}", gz_file, e),
+                        ),
+                        Ok(bases) => {
+                            if let Ok(taxid) = taxid.parse::<u64>() {
+                                let assembly = gz_path
+                                    .rsplit('/')
+                                    .next()
+                                    .unwrap_or_default()
+                                    .trim_end_matches("_genomic.fna.gz");
+                                assembly_sizes.lock().unwrap().push((
+                                    taxid,
+                                    assembly.to_string(),
+                                    bases,
+                                ));
+                            }
+                            fna_writer.flush().unwrap();
+                            map_writer.flush().unwrap();
+                            is_empty.fetch_and(false, Ordering::Relaxed);
+                        }
                     }
                 });
             }
@@ -259,6 +302,14 @@ fn merge_fna_parallel(
     let seqid2taxid_path = database.join("seqid2taxid.map");
     // merge_files(&fna_files, &library_fna_path)?;
     merge_files(&seqid_files, &seqid2taxid_path)?;
+
+    // 同一 taxid 的多个 assembly 取大小的中位数, 不随 assembly 数量累加
+    let assembly_sizes = assembly_sizes.into_inner().unwrap();
+    write_assembly_sizes(database.join(ASSEMBLY_SIZES_FILENAME), &assembly_sizes)?;
+    write_genome_sizes(
+        database.join(GENOME_SIZES_FILENAME),
+        &median_genome_sizes(&assembly_sizes),
+    )?;
     if is_empty.load(Ordering::Relaxed) {
         panic!("genimics fna files is empty! please check download dir");
     }
@@ -365,7 +416,11 @@ pub fn run(args: Args) -> Result<()> {
 #[allow(dead_code)]
 fn main() {
     let args = Args::parse();
-    if let Err(e) = run(args) {
+    if args.audit {
+        enable_audit();
+    }
+    let database = args.database.clone();
+    if let Err(e) = with_findings(database, || run(args)) {
         eprintln!("Application error: {}", e);
     }
 }
@@ -0,0 +1,144 @@
+use clap::Parser;
+use kun_peng::compact_hash::{embed_overflow_block, HashConfig};
+use kun_peng::utils::{find_and_sort_files, find_files, open_file};
+use std::fs::{self, create_dir_all};
+use std::io::{Error, ErrorKind, Read, Result};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Current version of the hash page layout
+const CURRENT_VERSION: usize = 1;
+
+#[derive(Parser, Debug, Clone)]
+#[clap(
+    version,
+    about = "Upgrade an older database (and chunk files) to the current format",
+    long_about = "Upgrade an older database to the current format.\nVersion 0 databases converted by hashshard are rewritten so every hash page carries its own overflow block (version 1)."
+)]
+pub struct Args {
+    /// database hash chunk directory and other files
+    #[arg(long = "db", required = true)]
+    pub database: PathBuf,
+
+    /// Write the migrated database to this directory instead of upgrading in place
+    #[clap(long, value_parser)]
+    pub output_db: Option<PathBuf>,
+
+    /// chunk directory with intermediate files of an unfinished classify run to check
+    #[clap(long, value_parser)]
+    pub chunk_dir: Option<PathBuf>,
+}
+
+/// Copies all regular files of the database directory
+fn copy_database(source: &Path, target: &Path) -> Result<()> {
+    create_dir_all(target)?;
+    for entry in fs::read_dir(source)? {
+        let path = entry?.path();
+        if path.is_file() {
+            fs::copy(&path, target.join(path.file_name().unwrap()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Upgrades version 0 hash pages to the version 1 layout
+fn migrate_hash_pages(database: &Path, config: &mut HashConfig) -> Result<()> {
+    let hash_files = find_and_sort_files(database, "hash", ".k2d", true)?;
+    if hash_files.len() != config.partition {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "found {} hash files, hash_config.k2d expects {}",
+                hash_files.len(),
+                config.partition
+            ),
+        ));
+    }
+
+    for (i, hash_file) in hash_files.iter().enumerate() {
+        let next_hash_file = &hash_files[(i + 1) % hash_files.len()];
+        let appended = embed_overflow_block(hash_file, next_hash_file)?;
+        if appended > 0 {
+            println!("{:?}: embedded {} overflow cells", hash_file, appended);
+        }
+    }
+
+    // 所有页面迁移完成后才更新版本号
+    config.version = CURRENT_VERSION;
+    config.write_to_file(database.join("hash_config.k2d"))
+}
+
+/// Checks the headers of chunk files left by an unfinished classify run
+///
+/// The chunk file layout is unchanged since version 0, so they only need to refer to
+/// existing pages of the database.
+fn check_chunk_files(chunk_dir: &Path, config: &HashConfig) -> Result<()> {
+    let chunk_files = find_files(chunk_dir, "sample", ".k2");
+    for chunk_file in &chunk_files {
+        let mut header = [0u8; 16];
+        open_file(chunk_file)?.read_exact(&mut header)?;
+        let page_index = u64::from_le_bytes(header[..8].try_into().unwrap()) as usize;
+        if page_index >= config.partition {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{:?} refers to page {}, the database has {} pages",
+                    chunk_file, page_index, config.partition
+                ),
+            ));
+        }
+    }
+    println!(
+        "{} chunk file(s) are already in the current format",
+        chunk_files.len()
+    );
+    Ok(())
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let start = Instant::now();
+    let database = match &args.output_db {
+        Some(output_db) => {
+            copy_database(&args.database, output_db)?;
+            output_db.clone()
+        }
+        None => args.database.clone(),
+    };
+
+    let mut config = HashConfig::from_hash_header(database.join("hash_config.k2d"))?;
+    println!("{:?}", config);
+    if config.version > CURRENT_VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "database version {} is newer than this binary supports ({})",
+                config.version, CURRENT_VERSION
+            ),
+        ));
+    }
+
+    if config.version < CURRENT_VERSION {
+        println!(
+            "migrate database {:?} from version {} to {}...",
+            database, config.version, CURRENT_VERSION
+        );
+        migrate_hash_pages(&database, &mut config)?;
+    } else {
+        println!("database is already at version {}", CURRENT_VERSION);
+    }
+
+    if let Some(chunk_dir) = &args.chunk_dir {
+        check_chunk_files(chunk_dir, &config)?;
+    }
+
+    println!("migrate took: {:?}", start.elapsed());
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Application error: {}", e);
+    }
+}
@@ -1,18 +1,39 @@
 use clap::Parser;
+use kun_peng::abundance::{
+    parse_spike_ins, rank_genome_sizes, read_genome_sizes, AbundanceMatrix, AbundanceOptions,
+    Normalization, GENOME_SIZES_FILENAME, SPIKE_IN_COLUMN,
+};
+use kun_peng::args::parse_size;
+use kun_peng::audit::{enable_audit, warn, write_findings};
 use kun_peng::classify::process_hitgroup;
 use kun_peng::compact_hash::{HashConfig, Row};
+use kun_peng::contamination::{
+    is_negative_control, write_contaminants, ControlProfile, NEGATIVE_CONTROL_COLUMN,
+};
+use kun_peng::coverage::{self, TAXON_MINIMIZERS_FILENAME};
+use kun_peng::heatmap::{HeatmapFormat, HitHeatmap};
+use kun_peng::interrupt::{
+    clear_incomplete_marker, interrupted, interrupted_error, write_incomplete_marker,
+};
+use kun_peng::metadata::SampleMetadata;
+use kun_peng::page_group::{hash_config_file, merge_group_rows};
+use kun_peng::plugin::{PostProcessors, ReadCall};
 use kun_peng::readcounts::{TaxonCounters, TaxonCountersDash};
-use kun_peng::report::report_kraken_style;
+use kun_peng::report::{get_clade_counts, report_kraken_style, ReportOptions};
 use kun_peng::taxonomy::Taxonomy;
-use kun_peng::utils::{find_and_trans_bin_files, find_and_trans_files, open_file};
+use kun_peng::utils::{
+    find_and_trans_bin_files, find_and_trans_files, open_file, read_sample_file_map, WriterConfig,
+};
 use kun_peng::HitGroup;
 // use rayon::prelude::*;
 use seqkmer::{buffer_map_parallel, trim_pair_info, OptionPair};
-use std::collections::HashMap;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
 use std::fs::{create_dir_all, File};
 use std::io::{self, BufRead, BufReader, BufWriter, Read, Result, Write};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Instant;
 
 pub fn read_id_to_seq_map<P: AsRef<Path>>(
@@ -46,6 +67,21 @@ pub fn read_id_to_seq_map<P: AsRef<Path>>(
     Ok(id_map)
 }
 
+/// Reads the multiplicities of reads that stand for collapsed duplicates (`splitr --dedup`)
+pub fn read_duplicate_counts<P: AsRef<Path>>(filename: P) -> Result<HashMap<u32, u64>> {
+    let reader = BufReader::new(open_file(filename)?);
+    let mut counts = HashMap::new();
+    for line in reader.lines() {
+        let line = line?;
+        if let Some((index, count)) = line.trim().split_once('\t') {
+            if let (Ok(index), Ok(count)) = (index.parse::<u32>(), count.parse::<u64>()) {
+                counts.insert(index, count);
+            }
+        }
+    }
+    Ok(counts)
+}
+
 #[derive(Parser, Debug, Clone)]
 #[clap(
     version,
@@ -89,6 +125,27 @@ pub struct Args {
     #[clap(short = 'z', long, value_parser, default_value_t = false)]
     pub report_zero_counts: bool,
 
+    /// Number of decimals of the percentage column in reports
+    #[clap(long, value_parser = clap::value_parser!(u8).range(0..=10), default_value_t = 2)]
+    pub report_precision: u8,
+
+    /// Separate report fields by tabs only: no padded percentages, no indented names
+    #[clap(long, value_parser, default_value_t = false)]
+    pub tab_only: bool,
+
+    /// Taxid written for unclassified reads in reports and per-read output
+    #[clap(long, value_parser, default_value_t = 0)]
+    pub unclassified_taxid: u32,
+
+    /// Report unclassified reads as a synthetic "unclassified" node under root
+    #[clap(long, value_parser, default_value_t = false)]
+    pub unclassified_under_root: bool,
+
+    /// Dynamic library with a per-read post-processing hook, can be repeated.
+    /// Requires the `dylib_plugins` feature.
+    #[clap(long = "plugin", value_parser)]
+    pub plugins: Vec<PathBuf>,
+
     /// The minimum number of hit groups needed for a call.
     #[clap(
         short = 'g',
@@ -97,6 +154,76 @@ pub struct Args {
         default_value_t = 2
     )]
     pub minimum_hit_groups: usize,
+
+    /// Buffer capacity of each per-sample output writer
+    #[clap(long, value_parser = parse_size, default_value = "8K")]
+    pub writer_buffer_size: usize,
+
+    /// Flush per-sample output writers once N seconds have passed since their last flush, checked whenever output is written; 0 flushes only when buffers are full
+    #[clap(long, default_value_t = 0)]
+    pub flush_interval: u64,
+
+    /// Tab separated sample sheet whose columns are copied into report and abundance table headers,
+    /// the abundance matrix and samples.json.
+    /// The first column names the sample by file index, input path or file name.
+    /// A spike_in column (taxid:quantity,...) declares spike-in controls for absolute abundances.
+    /// A negative_control column (true/false) marks blanks used to flag contaminants.
+    #[clap(long, value_parser)]
+    pub sample_metadata: Option<PathBuf>,
+
+    /// Export a taxon x read position heatmap of minimizer hits per sample with this many position bins
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..=1000), requires = "output_dir")]
+    pub heatmap_bins: Option<u64>,
+
+    /// File format of the hit heatmap
+    #[clap(long, value_enum, default_value_t = HeatmapFormat::Tsv)]
+    pub heatmap_format: HeatmapFormat,
+
+    /// Write the relative abundances of the taxa at this rank (e.g. species) per sample
+    /// to output_<i>.abundance.tsv, and for several samples a taxon x sample matrix
+    /// with the sample metadata to output_<min>-<max>.abundance_matrix.tsv
+    #[clap(long, requires = "output_dir")]
+    pub abundance_rank: Option<String>,
+
+    /// Denominator of the relative abundances
+    #[clap(long, value_enum, default_value_t = Normalization::Classified)]
+    pub abundance_normalization: Normalization,
+
+    /// Genome size table (taxid<TAB>bases) for genome-size normalization, default = $db/genome_sizes.tsv.
+    /// Databases built before the table was introduced need it passed explicitly.
+    #[clap(long, value_parser)]
+    pub genome_sizes: Option<PathBuf>,
+
+    /// Taxa whose share of the classified reads of a sample is at most this many times their
+    /// pooled share in the negative controls (negative_control column of the sample sheet) are
+    /// listed as likely contaminants. This is a plain sample-vs-blank share comparison, not
+    /// decontam's frequency method, which needs the input DNA concentration of each sample:
+    /// a contaminant that also makes up a large share of a sample is not flagged, and a
+    /// genuine taxon that is as rare in a sample as in the blanks is
+    #[clap(long, default_value_t = 2.0)]
+    pub contaminant_fold: f64,
+
+    /// Subtract the reads of each taxon in the negative controls, scaled by the ratio of the
+    /// sample's library size to the controls', from the other samples;
+    /// the subtracted reads are reported as unclassified, so the total stays unchanged
+    #[clap(long, action)]
+    pub subtract_controls: bool,
+
+    /// Add a minimizer fraction column to the reports: the estimated fraction of the distinct
+    /// reference minimizers of each clade that was hit in the sample. It counts minimizers, not
+    /// genome positions, so it is a proxy for rather than a measure of genome coverage breadth
+    #[clap(long, action)]
+    pub minimizer_fraction: bool,
+
+    /// Reference minimizer table (taxid<TAB>minimizers) for the minimizer fraction, default = $db/taxon_minimizers.tsv.
+    /// Databases built before the table was introduced need to be rebuilt.
+    #[clap(long, value_parser)]
+    pub taxon_minimizers: Option<PathBuf>,
+
+    /// Audit mode: collect parameter mismatches and data-quality warnings of the run
+    /// into findings.json in the output directory
+    #[clap(long, action, requires = "output_dir")]
+    pub audit: bool,
 }
 
 fn read_rows_from_file<P: AsRef<Path>>(file_path: P) -> io::Result<HashMap<u32, Vec<Row>>> {
@@ -113,14 +240,27 @@ fn read_rows_from_file<P: AsRef<Path>>(file_path: P) -> io::Result<HashMap<u32,
     Ok(map)
 }
 
+/// Taxonomy, settings and hooks shared by all samples of a run
+struct ResolveContext<'a> {
+    args: &'a Args,
+    taxonomy: &'a Taxonomy,
+    value_mask: usize,
+    post_processors: &'a PostProcessors,
+}
+
 fn process_batch<P: AsRef<Path>>(
     sample_files: &Vec<P>,
-    args: &Args,
-    taxonomy: &Taxonomy,
+    ctx: &ResolveContext,
     id_map: &HashMap<u32, (String, String, usize, Option<usize>)>,
     writer: &mut Box<dyn Write + Send>,
-    value_mask: usize,
+    heatmap: Option<&HitHeatmap>,
+    duplicates: Option<&HashMap<u32, u64>>,
+    sample: usize,
 ) -> Result<(TaxonCountersDash, usize)> {
+    let args = ctx.args;
+    let taxonomy = ctx.taxonomy;
+    let value_mask = ctx.value_mask;
+    let post_processors = ctx.post_processors;
     let confidence_threshold = args.confidence_threshold;
     let minimum_hit_groups = args.minimum_hit_groups;
 
@@ -137,11 +277,21 @@ fn process_batch<P: AsRef<Path>>(
                 if let Some(item) = id_map.get(&k) {
                     let mut rows = rows.to_owned();
                     rows.sort_unstable();
+                    // 同一个 minimizer 可能有多行: 多个页组都找到了它,
+                    // 或者 --resume 重新 annotate 了中断时的 chunk 文件
+                    merge_group_rows(&mut rows, value_mask, |a, b| taxonomy.lca(a, b));
 
                     let dna_id = trim_pair_info(&item.0);
                     let range =
                         OptionPair::from(((0, item.2), item.3.map(|size| (item.2, size + item.2))));
                     let hits = HitGroup::new(rows, range);
+                    // 去重后保留的 read 代表 multiplicity 条 read
+                    let multiplicity = duplicates
+                        .and_then(|counts| counts.get(k).copied())
+                        .unwrap_or(1);
+                    if let Some(heatmap) = heatmap {
+                        heatmap.add_hits(&hits, value_mask, multiplicity);
+                    }
 
                     let hit_data = process_hitgroup(
                         &hits,
@@ -152,22 +302,61 @@ fn process_batch<P: AsRef<Path>>(
                         value_mask,
                     );
 
-                    hit_data.3.iter().for_each(|(key, value)| {
+                    let mut taxon_counts = hit_data.3;
+                    if multiplicity > 1 {
+                        taxon_counts
+                            .values_mut()
+                            .for_each(|counter| counter.multiply(multiplicity));
+                        if hit_data.0 == "C" {
+                            classify_counter.fetch_add(multiplicity as usize - 1, Ordering::SeqCst);
+                        }
+                    }
+
+                    let classified = hit_data.0 == "C";
+                    let mut call = ReadCall {
+                        classified,
+                        read_id: dna_id.to_string(),
+                        taxid: if classified {
+                            hit_data.1
+                        } else {
+                            u64::from(args.unclassified_taxid)
+                        },
+                        size: item.1.clone(),
+                        hit_list: hit_data.2,
+                        annotations: Vec::new(),
+                    };
+                    if duplicates.is_some() {
+                        call.annotations.push(multiplicity.to_string());
+                    }
+                    // 插件可以修改或丢弃这条输出, 报告按插件处理后的结果计数
+                    let (kept, counted) = post_processors.apply_counted(
+                        &mut call,
+                        multiplicity,
+                        taxonomy,
+                        &mut taxon_counts,
+                    );
+                    let reads = multiplicity as usize;
+                    match (classified, counted) {
+                        (false, true) => classify_counter.fetch_add(reads, Ordering::SeqCst),
+                        (true, false) => classify_counter.fetch_sub(reads, Ordering::SeqCst),
+                        _ => 0,
+                    };
+
+                    taxon_counts.iter().for_each(|(key, value)| {
                         cur_taxon_counts
                             .entry(*key)
                             .or_default()
                             .merge(value)
                             .unwrap();
                     });
-
-                    // 使用锁来同步写入
-                    let output_line = format!(
-                        "{}\t{}\t{}\t{}\t{}\n",
-                        hit_data.0, dna_id, hit_data.1, item.1, hit_data.2
-                    );
-                    Some(output_line)
+                    kept.then(|| call.to_line())
                 } else {
-                    eprintln!("can't find {} in sample_id map file", k);
+                    warn(
+                        "resolve",
+                        "missing_sequence_id",
+                        Some(sample),
+                        &format!("can't find {} in sample_id map file", k),
+                    );
                     None
                 }
             },
@@ -187,7 +376,93 @@ fn process_batch<P: AsRef<Path>>(
     Ok((cur_taxon_counts, classify_counter.load(Ordering::SeqCst)))
 }
 
+/// Reads per taxon, without the k-mer counters
+fn call_counts(taxon_counts: &TaxonCounters) -> HashMap<u64, u64> {
+    taxon_counts
+        .iter()
+        .map(|(&taxid, counter)| (taxid, counter.read_count()))
+        .collect()
+}
+
+/// Checks the abundance rank and loads the genome sizes of its taxa
+pub fn load_abundance_options(
+    args: &Args,
+    taxonomy: &Taxonomy,
+    rank: &str,
+) -> Result<AbundanceOptions> {
+    let has_rank = (1..taxonomy.node_count() as u32).any(|taxid| taxonomy.rank_of(taxid) == rank);
+    if !has_rank {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("no taxon of rank {} in the taxonomy", rank),
+        ));
+    }
+
+    let filename = args
+        .genome_sizes
+        .clone()
+        .unwrap_or_else(|| args.database.join(GENOME_SIZES_FILENAME));
+    // 非基因组大小归一化时, 大小表只是附加信息, 可以缺失
+    let sizes = if filename.exists() {
+        read_genome_sizes(&filename)?
+    } else if args.abundance_normalization == Normalization::GenomeSize {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "genome size table {:?} not found, rebuild the database or pass --genome-sizes",
+                filename
+            ),
+        ));
+    } else {
+        HashMap::new()
+    };
+
+    Ok(AbundanceOptions {
+        rank: rank.to_string(),
+        normalization: args.abundance_normalization,
+        genome_sizes: rank_genome_sizes(taxonomy, &sizes, rank),
+        sizes,
+    })
+}
+
+/// Loads the reference minimizers per clade for the minimizer fraction column
+pub fn load_clade_minimizers(args: &Args, taxonomy: &Taxonomy) -> Result<Arc<HashMap<u64, u64>>> {
+    let filename = args
+        .taxon_minimizers
+        .clone()
+        .unwrap_or_else(|| args.database.join(TAXON_MINIMIZERS_FILENAME));
+    Ok(Arc::new(coverage::load_clade_minimizers(filename, taxonomy)?))
+}
+
+/// Warns about options that have no effect with the other options given
+fn check_parameters(args: &Args) {
+    let mismatch = |message: &str| warn("resolve", "parameter_mismatch", None, message);
+    if args.heatmap_bins.is_none() && args.heatmap_format != HeatmapFormat::Tsv {
+        mismatch("--heatmap-format has no effect without --heatmap-bins");
+    }
+    if args.abundance_rank.is_none() {
+        if args.abundance_normalization != Normalization::Classified {
+            mismatch("--abundance-normalization has no effect without --abundance-rank");
+        }
+        if args.genome_sizes.is_some() {
+            mismatch("--genome-sizes has no effect without --abundance-rank");
+        }
+    }
+    if args.taxon_minimizers.is_some() && !args.minimizer_fraction {
+        mismatch("--taxon-minimizers has no effect without --minimizer-fraction");
+    }
+    if args.subtract_controls && args.sample_metadata.is_none() {
+        mismatch(
+            "--subtract-controls needs a --sample-metadata sheet with a negative_control column",
+        );
+    }
+}
+
 pub fn run(args: Args) -> Result<()> {
+    if args.audit {
+        enable_audit();
+    }
+    check_parameters(&args);
     let k2d_dir = &args.database;
     let taxonomy_filename = k2d_dir.join("taxo.k2d");
     let taxo = Taxonomy::from_file(taxonomy_filename)?;
@@ -196,9 +471,41 @@ pub fn run(args: Args) -> Result<()> {
     let sample_id_files = find_and_trans_files(&args.chunk_dir, "sample_id", ".map", false)?;
 
     // let partition = sample_files.len();
-    let hash_config = HashConfig::from_hash_header(&args.database.join("hash_config.k2d"))?;
+    let hash_config = HashConfig::from_hash_header(&hash_config_file(&args.database)?)?;
     let value_mask = hash_config.value_mask;
 
+    let writer_config = WriterConfig::new(args.writer_buffer_size, args.flush_interval);
+    let report_options = ReportOptions {
+        precision: args.report_precision as usize,
+        tab_only: args.tab_only,
+        unclassified_taxid: args.unclassified_taxid,
+        unclassified_under_root: args.unclassified_under_root,
+        clade_minimizers: if args.minimizer_fraction {
+            Some(load_clade_minimizers(&args, &taxo)?)
+        } else {
+            None
+        },
+        ..ReportOptions::new(args.report_zero_counts, args.report_kmer_data)
+    };
+    let sample_metadata = match &args.sample_metadata {
+        Some(sheet) => Some(SampleMetadata::from_file(sheet)?),
+        None => None,
+    };
+    let abundance = match &args.abundance_rank {
+        Some(rank) => Some(load_abundance_options(&args, &taxo, rank)?),
+        None => None,
+    };
+    let post_processors = PostProcessors::from_libraries(&args.plugins)?;
+    let ctx = ResolveContext {
+        args: &args,
+        taxonomy: &taxo,
+        value_mask,
+        post_processors: &post_processors,
+    };
+    let sample_paths =
+        read_sample_file_map(args.chunk_dir.join("sample_file.map")).unwrap_or_default();
+    let mut sample_summaries = Vec::new();
+
     let mut total_taxon_counts = TaxonCounters::new();
     let mut total_seqs = 0;
     let mut total_unclassified = 0;
@@ -211,26 +518,71 @@ pub fn run(args: Args) -> Result<()> {
     let start = Instant::now();
     println!("resolve start...");
 
-    for (i, sam_files) in &sample_files {
+    // 阴性对照先处理, 其计数用来标记其他样本中的污染
+    let controls: HashSet<usize> = sample_files
+        .keys()
+        .copied()
+        .filter(|i| {
+            let paths = sample_paths.get(i).cloned().unwrap_or_default();
+            sample_metadata.as_ref().is_some_and(|sheet| {
+                sheet.lookup(*i, &paths).iter().any(|(key, value)| {
+                    key == NEGATIVE_CONTROL_COLUMN && is_negative_control(value)
+                })
+            })
+        })
+        .collect();
+    if args.subtract_controls && controls.is_empty() {
+        warn(
+            "resolve",
+            "parameter_mismatch",
+            None,
+            "no negative controls in the sample sheet, nothing to subtract",
+        );
+    }
+    let mut order: Vec<usize> = sample_files.keys().copied().collect();
+    order.sort_by_key(|i| (!controls.contains(i), *i));
+    let mut control_profile = ControlProfile::default();
+    // 对照先处理, 矩阵的列按样本编号排列
+    let mut matrix_columns = Vec::new();
+
+    let mut completed = Vec::new();
+    for i in &order {
+        if interrupted().is_some() {
+            break;
+        }
+        let sam_files = &sample_files[i];
         let sample_id_map = read_id_to_seq_map(&sample_id_files[i])?;
+        let dup_file = args.chunk_dir.join(format!("sample_dup_{}.map", i));
+        let duplicates = if dup_file.exists() {
+            Some(read_duplicate_counts(&dup_file)?)
+        } else {
+            None
+        };
 
-        let thread_sequences = sample_id_map.len();
+        let collapsed: u64 = duplicates
+            .iter()
+            .flat_map(|counts| counts.values().map(|count| count - 1))
+            .sum();
+        let thread_sequences = sample_id_map.len() + collapsed as usize;
         let mut writer: Box<dyn Write + Send> = match &args.output_dir {
             Some(ref file_path) => {
                 let filename = file_path.join(format!("output_{}.txt", i));
                 let file = File::create(filename)?;
-                Box::new(BufWriter::new(file)) as Box<dyn Write + Send>
+                Box::new(writer_config.writer(file)) as Box<dyn Write + Send>
             }
-            None => Box::new(BufWriter::new(io::stdout())) as Box<dyn Write + Send>,
+            None => Box::new(writer_config.writer(io::stdout())) as Box<dyn Write + Send>,
         };
-        let (thread_taxon_counts, thread_classified) = process_batch::<PathBuf>(
+        let heatmap = args.heatmap_bins.map(|bins| HitHeatmap::new(bins as usize));
+        let (thread_taxon_counts, mut thread_classified) = process_batch::<PathBuf>(
             sam_files,
-            &args,
-            &taxo,
+            &ctx,
             &sample_id_map,
             &mut writer,
-            value_mask,
+            heatmap.as_ref(),
+            duplicates.as_ref(),
+            *i,
         )?;
+        ctx.post_processors.tally_remapped("resolve", *i);
 
         let mut sample_taxon_counts: HashMap<
             u64,
@@ -239,32 +591,169 @@ pub fn run(args: Args) -> Result<()> {
             >,
         > = HashMap::new();
         thread_taxon_counts.iter().for_each(|entry| {
-            total_taxon_counts
+            sample_taxon_counts
                 .entry(*entry.key())
                 .or_default()
                 .merge(&entry.value())
                 .unwrap();
-            sample_taxon_counts
-                .entry(*entry.key())
+        });
+
+        let is_control = controls.contains(i);
+        let mut contaminants = Vec::new();
+        let mut removed = 0;
+        if is_control {
+            let sequences = thread_sequences as u64;
+            control_profile.add_sample(&call_counts(&sample_taxon_counts), sequences);
+        } else if !control_profile.is_empty() {
+            contaminants = control_profile
+                .likely_contaminants(&call_counts(&sample_taxon_counts), args.contaminant_fold);
+            if args.subtract_controls {
+                let mut emptied = Vec::new();
+                for (taxid, counter) in sample_taxon_counts.iter_mut() {
+                    // 按样本和阴性对照的测序量之比缩放
+                    let reads = control_profile
+                        .expected_reads(*taxid, thread_sequences as u64)
+                        .min(counter.read_count());
+                    if reads == 0 {
+                        continue;
+                    }
+                    counter.remove_reads(reads);
+                    removed += reads as usize;
+                    if counter.read_count() == 0 {
+                        emptied.push(*taxid);
+                    }
+                }
+                // 只删除被扣到 0 的 taxon, 只有 k-mer 命中的 taxon 保留
+                for taxid in emptied {
+                    sample_taxon_counts.remove(&taxid);
+                }
+                // 扣除的 reads 记为未分类, 样本总数不变
+                thread_classified -= removed;
+            }
+        }
+        for (taxid, counter) in &sample_taxon_counts {
+            total_taxon_counts
+                .entry(*taxid)
                 .or_default()
-                .merge(&entry.value())
+                .merge(counter)
                 .unwrap();
+        }
+        let paths = sample_paths.get(i).cloned().unwrap_or_default();
+        let metadata = sample_metadata
+            .as_ref()
+            .map(|sheet| sheet.lookup(*i, &paths))
+            .unwrap_or_default();
+        // 污染统计跟样本表的列一起写进报告头
+        let mut report_metadata = metadata.clone();
+        let mut summary = json!({
+            "index": i,
+            "files": paths,
+            "total_sequences": thread_sequences,
+            "classified": thread_classified,
+            "unclassified": thread_sequences - thread_classified,
         });
+        if !is_control && !control_profile.is_empty() {
+            let count = contaminants.len().to_string();
+            report_metadata.push(("likely_contaminants".to_string(), count));
+            summary["likely_contaminants"] = contaminants.len().into();
+            if args.subtract_controls {
+                let removed_reads = removed.to_string();
+                report_metadata.push(("contaminant_reads_removed".to_string(), removed_reads));
+                summary["contaminant_reads_removed"] = removed.into();
+            }
+        }
+        let spike_ins = match metadata.iter().find(|(key, _)| key == SPIKE_IN_COLUMN) {
+            Some((_, value)) => parse_spike_ins(value)?,
+            None => Vec::new(),
+        };
+        if !spike_ins.is_empty() && abundance.is_none() {
+            warn(
+                "resolve",
+                "parameter_mismatch",
+                Some(*i),
+                &format!(
+                    "sample {} declares spike-ins, pass --abundance-rank to get absolute abundances",
+                    i
+                ),
+            );
+        }
+        for (taxid, _) in &spike_ins {
+            if taxo.get_internal_id(*taxid) == 0 {
+                warn(
+                    "resolve",
+                    "unknown_taxid",
+                    Some(*i),
+                    &format!(
+                        "spike-in taxid {} of sample {} is not in the taxonomy",
+                        taxid, i
+                    ),
+                );
+            }
+        }
         if let Some(output) = &args.output_dir {
             let filename = output.join(format!("output_{}.kreport2", i));
             report_kraken_style(
                 filename,
-                args.report_zero_counts,
-                args.report_kmer_data,
+                &report_options.with_metadata(report_metadata.clone()),
                 &taxo,
                 &sample_taxon_counts,
                 thread_sequences as u64,
                 (thread_sequences - thread_classified) as u64,
             )?;
+            if let Some(heatmap) = &heatmap {
+                let format = args.heatmap_format;
+                let filename = output.join(format!("heatmap_{}.{}", i, format.extension()));
+                heatmap.write(filename, format, &taxo)?;
+            }
+            if !is_control && !control_profile.is_empty() {
+                let filename = output.join(format!("output_{}.contaminants.tsv", i));
+                write_contaminants(filename, &contaminants, &taxo)?;
+            }
+            if let Some(abundance) = &abundance {
+                let counts = call_counts(&sample_taxon_counts);
+                let spike_in_ids: Vec<u64> = spike_ins.iter().map(|&(taxid, _)| taxid).collect();
+                let total = thread_sequences as u64;
+                let unclassified = (thread_sequences - thread_classified) as u64;
+                abundance.write_table(
+                    output.join(format!("output_{}.abundance.tsv", i)),
+                    &taxo,
+                    &counts,
+                    total,
+                    unclassified,
+                    &report_metadata,
+                )?;
+                let clade_counts = get_clade_counts(&taxo, &counts);
+                matrix_columns.push((
+                    *i,
+                    report_metadata.clone(),
+                    abundance.abundances(&taxo, &clade_counts, total, unclassified, &spike_in_ids),
+                ));
+            }
         }
 
         total_seqs += thread_sequences;
         total_unclassified += thread_sequences - thread_classified;
+        completed.push(*i);
+        summary["metadata"] = metadata
+            .into_iter()
+            .map(|(key, value)| (key, value.into()))
+            .collect::<serde_json::Map<_, _>>()
+            .into();
+        sample_summaries.push(summary);
+    }
+
+    if interrupted().is_some() {
+        // 已完成样本的输出是完整的, bin 文件保留给重新运行
+        if let Some(output) = &args.output_dir {
+            let completed: Vec<String> = completed.iter().map(|i| i.to_string()).collect();
+            write_incomplete_marker(
+                output,
+                "resolve",
+                &format!("completed_samples\t{}", completed.join(",")),
+            )?;
+            write_findings(output)?;
+        }
+        return Err(interrupted_error("resolve"));
     }
 
     if let Some(output) = &args.output_dir {
@@ -276,19 +765,48 @@ pub fn run(args: Args) -> Result<()> {
                 let filename = output.join(format!("output_{}-{}.kreport2", min, max));
                 report_kraken_style(
                     filename,
-                    args.report_zero_counts,
-                    args.report_kmer_data,
+                    &report_options,
                     &taxo,
                     &total_taxon_counts,
                     total_seqs as u64,
                     total_unclassified as u64,
                 )?;
+                if let Some(abundance) = &abundance {
+                    abundance.write_table(
+                        output.join(format!("output_{}-{}.abundance.tsv", min, max)),
+                        &taxo,
+                        &call_counts(&total_taxon_counts),
+                        total_seqs as u64,
+                        total_unclassified as u64,
+                        &[],
+                    )?;
+                    matrix_columns.sort_by_key(|(i, _, _)| *i);
+                    let mut abundance_matrix = AbundanceMatrix::default();
+                    for (i, metadata, abundances) in matrix_columns.drain(..) {
+                        abundance_matrix.add_sample(&i.to_string(), metadata, &abundances);
+                    }
+                    abundance_matrix.write(
+                        output.join(format!("output_{}-{}.abundance_matrix.tsv", min, max)),
+                        abundance,
+                        &taxo,
+                    )?;
+                }
             }
 
             let source_sample_file = args.chunk_dir.join("sample_file.map");
             let to_sample_file = output.join("sample_file.txt");
             std::fs::copy(source_sample_file, to_sample_file)?;
+
+            if sample_metadata.is_some() {
+                sample_summaries.sort_by_key(|summary| summary["index"].as_u64());
+                let writer = BufWriter::new(File::create(output.join("samples.json"))?);
+                serde_json::to_writer_pretty(writer, &sample_summaries)?;
+            }
         };
+        if let Some(filename) = write_findings(output)? {
+            println!("findings written to {}", filename.display());
+        }
+        clear_incomplete_marker(output);
     }
 
     // 计算持续时间
@@ -302,8 +820,9 @@ pub fn run(args: Args) -> Result<()> {
         }
     }
 
-    for (_, sample_file) in sample_id_files {
+    for (i, sample_file) in sample_id_files {
         let _ = std::fs::remove_file(sample_file);
+        let _ = std::fs::remove_file(args.chunk_dir.join(format!("sample_dup_{}.map", i)));
     }
     // let source_sample_file = args.chunk_dir.join("sample_file.map");
     // let _ = std::fs::remove_file(source_sample_file);
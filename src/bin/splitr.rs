@@ -1,15 +1,23 @@
 use clap::Parser;
-use kun_peng::compact_hash::{HashConfig, Slot};
+use kun_peng::audit::{enable_audit, tally, warn, with_findings};
+use kun_peng::compact_hash::{HashConfig, Row, Slot};
+use kun_peng::inputs::{expand_input_files, pair_read_files, DEFAULT_PAIR_REGEX};
+use kun_peng::interrupt::{interrupted, interrupted_error, Interruptible};
 use kun_peng::utils::{
-    create_partition_files, create_partition_writers, create_sample_file, get_file_limit,
-    get_lastest_file_index, set_fd_limit,
+    available_space, create_partition_files, create_partition_writers, create_sample_file,
+    format_bytes, get_file_limit, get_lastest_file_index, set_fd_limit, DiskCheck,
 };
 use kun_peng::IndexOptions;
-use seqkmer::{read_parallel, FastxReader, Meros, MinimizerIterator, OptionPair, Reader};
+use seahash::SeaHasher;
+use seqkmer::{read_parallel, Base, FastxReader, Meros, MinimizerIterator, OptionPair, Reader};
+use std::collections::HashMap;
 use std::fs;
+use std::hash::Hasher;
 use std::io::{BufWriter, Write};
 use std::io::{Error, ErrorKind, Result};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
 use std::time::Instant;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
@@ -57,9 +65,40 @@ pub struct Args {
     #[clap(long)]
     pub chunk_dir: PathBuf,
 
+    /// Regex with one capture group for the mate number (1 or 2) in read file names,
+    /// used to pair files expanded from directories and glob patterns.
+    /// Explicitly listed files are paired with it too when given.
+    #[clap(long)]
+    pub pair_regex: Option<String>,
+
+    /// Check the free space of chunk_dir against the estimated size of the chunk files before splitting
+    #[clap(long, value_enum, default_value_t = DiskCheck::Error)]
+    pub disk_check: DiskCheck,
+
+    /// Collapse exact duplicate reads (same sequence, both mates of a pair) into one,
+    /// their multiplicity is carried through to the read counts of the reports.
+    #[clap(long, action)]
+    pub dedup: bool,
+
+    /// Distinct reads tracked per sample by --dedup (about 50 bytes each),
+    /// once reached only copies of the reads already tracked are collapsed
+    #[clap(long, default_value_t = 10_000_000)]
+    pub dedup_max_reads: usize,
+
+    /// Only write the chunk files, the sample maps of the inputs were written by an
+    /// earlier run over another page group of the same database
+    #[clap(skip)]
+    pub hits_only: bool,
+
+    /// Audit mode: collect data-quality warnings, like reads without minimizers or
+    /// truncated inputs, into findings.json in the chunk directory
+    #[clap(long, action)]
+    pub audit: bool,
+
     /// A list of input file paths (FASTA/FASTQ) to be processed by the classify program.
     /// Supports fasta or fastq format files (e.g., .fasta, .fastq) and gzip compressed files (e.g., .fasta.gz, .fastq.gz).
-    /// Can also be a single .txt file containing a list of input file paths, one per line.
+    /// Can also be a single .txt file containing a list of input file paths, one per line,
+    /// directories (searched recursively for read files) or quoted glob patterns.
     #[clap(required = true)]
     pub input_files: Vec<PathBuf>,
 }
@@ -92,6 +131,17 @@ impl Args {
             }
         }
 
+        let (input_files, expanded) = expand_input_files(&self.input_files)?;
+        self.input_files = input_files;
+        if self.paired_end_processing
+            && !self.single_file_pairs
+            && (expanded || self.pair_regex.is_some())
+        {
+            // 目录和通配符展开的文件按 R1/R2 配对
+            let pair_regex = self.pair_regex.as_deref().unwrap_or(DEFAULT_PAIR_REGEX);
+            self.input_files = pair_read_files(&self.input_files, pair_regex)?;
+        }
+
         // Final check for all input files
         let mut missing_files = Vec::new();
         for file in &self.input_files {
@@ -116,6 +166,100 @@ impl Args {
     }
 }
 
+/// Assumed compression ratio of gzip compressed read files
+const GZIP_RATIO: f64 = 4.0;
+
+/// Estimates the bytes splitr writes to chunk_dir, and the bytes annotate adds on top
+///
+/// Only file sizes are used: FASTQ files are assumed to be half bases, FASTA files
+/// all bases. Minimizers are expected every (window + 2) / 2 bases, each one is
+/// written as a slot to a chunk file and, for hits, as a row to a bin file.
+fn estimate_chunk_bytes(input_files: &[PathBuf], meros: &Meros) -> (u64, u64) {
+    let mut bases = 0.0;
+    let mut map_bytes = 0.0;
+    for file in input_files {
+        let mut size = fs::metadata(file).map(|m| m.len()).unwrap_or(0) as f64;
+        let name = file.to_string_lossy().to_ascii_lowercase();
+        let name = match name.strip_suffix(".gz") {
+            Some(name) => {
+                size *= GZIP_RATIO;
+                name.to_string()
+            }
+            None => name,
+        };
+        if name.ends_with(".fq") || name.ends_with(".fastq") {
+            bases += size * 0.5;
+            // sample_id map 每条 read 一行
+            map_bytes += size * 0.15;
+        } else {
+            bases += size;
+            map_bytes += size * 0.01;
+        }
+    }
+
+    let minimizers = bases * 2.0 / (meros.window_size() + 2) as f64;
+    let slot_size = std::mem::size_of::<Slot<u64>>() as f64;
+    let row_size = std::mem::size_of::<Row>() as f64;
+    (
+        (minimizers * slot_size + map_bytes) as u64,
+        (minimizers * row_size) as u64,
+    )
+}
+
+/// Compares the estimated size of the intermediate files with the free space of chunk_dir
+fn check_disk_space(args: &Args, meros: &Meros) -> Result<()> {
+    if args.disk_check == DiskCheck::Off {
+        return Ok(());
+    }
+    let available = match available_space(&args.chunk_dir) {
+        Some(available) => available,
+        None => {
+            warn(
+                "splitr",
+                "disk_check_skipped",
+                None,
+                &format!(
+                    "can't determine the free space of {:?}, skip disk check",
+                    args.chunk_dir
+                ),
+            );
+            return Ok(());
+        }
+    };
+    let (chunk_bytes, annotate_bytes) = estimate_chunk_bytes(&args.input_files, meros);
+    println!(
+        "estimated chunk files: {}, annotate output up to {}, available: {}",
+        format_bytes(chunk_bytes as f64),
+        format_bytes(annotate_bytes as f64),
+        format_bytes(available as f64)
+    );
+
+    if chunk_bytes > available {
+        let msg = format!(
+            "chunk_dir {:?} has {} free, splitr is estimated to need {}",
+            args.chunk_dir,
+            format_bytes(available as f64),
+            format_bytes(chunk_bytes as f64)
+        );
+        if args.disk_check == DiskCheck::Error {
+            return Err(Error::other(format!(
+                "{}, free up space, use another --chunk-dir or pass --disk-check warn",
+                msg
+            )));
+        }
+        warn("splitr", "disk_space", None, &msg);
+    } else if chunk_bytes + annotate_bytes > available {
+        // annotate 处理完一个 chunk 文件才删除它
+        let msg = format!(
+            "chunk_dir {:?} may run out of space during annotate, up to {} needed",
+            args.chunk_dir,
+            format_bytes((chunk_bytes + annotate_bytes) as f64)
+        );
+        warn("splitr", "disk_space", None, &msg);
+    }
+    Ok(())
+}
+
 fn init_chunk_writers(
     args: &Args,
     partition: usize,
@@ -150,41 +294,169 @@ fn init_chunk_writers(
     writers
 }
 
+/// 重复 read 的判定键: 每条 mate 的长度和序列, 两个独立的 64 位哈希
+fn sequence_key(seq: &Base<Vec<u8>>) -> u128 {
+    let mut hashers = (SeaHasher::new(), SeaHasher::with_seeds(1, 2, 3, 4));
+    seq.body.apply(|bases| {
+        for hasher in [&mut hashers.0, &mut hashers.1] {
+            hasher.write_u64(bases.len() as u64);
+            hasher.write(bases);
+        }
+    });
+    (hashers.0.finish() as u128) << 64 | hashers.1.finish() as u128
+}
+
+/// Reader passing on only the first copy of exact duplicate reads, for `--dedup`
+///
+/// Reads are compared by their sequence after quality masking, so copies keep the
+/// same classification. At most `max_reads` distinct reads are tracked, later
+/// new reads are passed on without being tracked.
+struct DedupReader<R> {
+    inner: R,
+    /// 序列哈希 -> (第一条 read 的编号, 重复次数)
+    seen: HashMap<u128, (usize, u64)>,
+    max_reads: usize,
+}
+
+impl<R: Reader> DedupReader<R> {
+    fn new(inner: R, max_reads: usize) -> Self {
+        Self {
+            inner,
+            seen: HashMap::new(),
+            max_reads,
+        }
+    }
+
+    /// Whether the read is the first copy, counts it either way
+    fn keep(&mut self, seq: &Base<Vec<u8>>) -> bool {
+        let key = sequence_key(seq);
+        let index = seq.header.reads_index;
+        if let Some(entry) = self.seen.get_mut(&key) {
+            entry.1 += 1;
+            return false;
+        }
+        if self.seen.len() < self.max_reads {
+            self.seen.insert(key, (index, 1));
+        }
+        true
+    }
+}
+
+impl<R: Reader> Reader for DedupReader<R> {
+    fn next(&mut self) -> Result<Option<Vec<Base<Vec<u8>>>>> {
+        let seqs = match self.inner.next()? {
+            Some(seqs) if self.max_reads > 0 => seqs,
+            other => return Ok(other),
+        };
+        Ok(Some(seqs.into_iter().filter(|seq| self.keep(seq)).collect()))
+    }
+}
+
+/// Reader counting the bases masked by `--minimum-quality-score`
+///
+/// seqkmer replaces the bases below the quality threshold with `x` while reading
+/// FASTQ records, so the masked bases are counted after reading.
+struct MaskCounter<R> {
+    inner: R,
+    enabled: bool,
+    bases: u64,
+    reads: u64,
+}
+
+impl<R: Reader> MaskCounter<R> {
+    fn new(inner: R, enabled: bool) -> Self {
+        Self {
+            inner,
+            enabled,
+            bases: 0,
+            reads: 0,
+        }
+    }
+}
+
+impl<R: Reader> Reader for MaskCounter<R> {
+    fn next(&mut self) -> Result<Option<Vec<Base<Vec<u8>>>>> {
+        let seqs = self.inner.next()?;
+        if let (Some(seqs), true) = (&seqs, self.enabled) {
+            for seq in seqs {
+                let masked = seq.body.reduce(0, |n, bases| {
+                    n + bases.iter().filter(|&&base| base == b'x').count()
+                });
+                if masked > 0 {
+                    self.bases += masked as u64;
+                    self.reads += 1;
+                }
+            }
+        }
+        Ok(seqs)
+    }
+}
+
+/// Sequences of at least this many bases have their slots written in batches
+const LONG_SEQUENCE_LEN: usize = 1 << 20;
+
+/// Number of slots of a long sequence buffered before they are written
+const SLOT_BATCH_SIZE: usize = 1 << 16;
+
+/// 一条序列(或一对 mate)的 slot 缓冲
+struct SlotBatch<'a> {
+    slots: Vec<(usize, Slot<u64>)>,
+    /// 已生成的 slot 数量, 第二条 mate 的位置从这里开始
+    count: usize,
+    /// 长序列的 slot 分批直接写入 chunk 文件, 不在内存中保留整条序列的 slot
+    stream_to: Option<&'a Mutex<&'a mut Vec<BufWriter<fs::File>>>>,
+}
+
 /// 处理record
 fn process_record(
-    k2_slot_list: &mut Vec<(usize, Slot<u64>)>,
+    batch: &mut SlotBatch,
     marker: &mut MinimizerIterator,
     hash_config: &HashConfig,
     chunk_size: usize,
     seq_id: u64,
     idx_bits: usize,
 ) {
-    let offset = k2_slot_list.len();
+    let offset = batch.count;
     for (sort, hash_key) in marker {
         let mut slot = hash_config.slot_u64(hash_key, seq_id);
         let seq_sort = sort + offset;
         let partition_index = slot.idx / chunk_size;
 
         slot.idx = seq_sort << idx_bits | (slot.idx % chunk_size);
-        k2_slot_list.push((partition_index, slot));
+        batch.slots.push((partition_index, slot));
+        batch.count += 1;
+
+        if batch.slots.len() >= SLOT_BATCH_SIZE {
+            if let Some(writers) = batch.stream_to {
+                write_slots(&mut writers.lock().unwrap(), batch.slots.drain(..));
+            }
+        }
     }
 }
 
-fn write_data_to_file(
-    k2_map: String,
-    k2_slot_list: Vec<(usize, Slot<u64>)>,
-    writers: &mut Vec<BufWriter<fs::File>>,
-    slot_size: usize,
-    sample_writer: &mut BufWriter<fs::File>,
-) {
-    for slot in k2_slot_list {
+fn write_slots<I>(writers: &mut [BufWriter<fs::File>], slots: I)
+where
+    I: IntoIterator<Item = (usize, Slot<u64>)>,
+{
+    let slot_size = std::mem::size_of::<Slot<u64>>();
+    for slot in slots {
         let partition_index = slot.0;
         if let Some(writer) = writers.get_mut(partition_index) {
             writer.write_all(slot.1.as_slice(slot_size)).unwrap();
         }
     }
+}
 
-    sample_writer.write_all(k2_map.as_bytes()).unwrap();
+fn write_data_to_file(
+    k2_map: String,
+    k2_slot_list: Vec<(usize, Slot<u64>)>,
+    writers: &mut [BufWriter<fs::File>],
+    sample_writer: Option<&mut BufWriter<fs::File>>,
+) {
+    write_slots(writers, k2_slot_list);
+    if let Some(sample_writer) = sample_writer {
+        sample_writer.write_all(k2_map.as_bytes()).unwrap();
+    }
 }
 
 fn process_fastx_file<R>(
@@ -194,14 +466,16 @@ fn process_fastx_file<R>(
     file_index: usize,
     reader: &mut R,
     writers: &mut Vec<BufWriter<fs::File>>,
-    sample_writer: &mut BufWriter<fs::File>,
+    mut sample_writer: Option<&mut BufWriter<fs::File>>,
 ) -> Result<()>
 where
     R: Reader,
 {
     let chunk_size = hash_config.hash_capacity;
     let idx_bits = ((chunk_size as f64).log2().ceil() as usize).max(1);
-    let slot_size = std::mem::size_of::<Slot<u64>>();
+    let writers = Mutex::new(writers);
+    // 没有 minimizer 的 read: 短于 k 或被质量值整条屏蔽
+    let no_minimizers = AtomicU64::new(0);
 
     read_parallel(
         reader,
@@ -211,15 +485,21 @@ where
             let mut buffer = String::new();
             let mut k2_slot_list = Vec::new();
             for seq in seqs {
-                let mut init: Vec<(usize, Slot<u64>)> = Vec::new();
                 let header = &seq.header;
                 let index = header.reads_index;
                 let dna_id = header.id.trim();
                 let seq_id = (file_index << 32 | index) as u64;
+                let long =
+                    seq.body.reduce(0, |len, m_iter| len + m_iter.seq_size()) >= LONG_SEQUENCE_LEN;
+                let mut batch = SlotBatch {
+                    slots: Vec::new(),
+                    count: 0,
+                    stream_to: long.then_some(&writers),
+                };
 
                 seq.body.apply_mut(|m_iter| {
                     process_record(
-                        &mut init,
+                        &mut batch,
                         m_iter,
                         &hash_config,
                         chunk_size,
@@ -227,7 +507,10 @@ where
                         idx_bits,
                     );
                 });
-                k2_slot_list.extend_from_slice(&init);
+                if batch.count == 0 {
+                    no_minimizers.fetch_add(1, Ordering::Relaxed);
+                }
+                k2_slot_list.extend(batch.slots);
 
                 let size_str = seq.fmt_size();
                 let seq_size_str = seq.fmt_seq_size();
@@ -240,23 +523,79 @@ where
         |dataset| {
             while let Some(data) = dataset.next() {
                 let (buffer, k2_slot_list) = data.unwrap();
-                write_data_to_file(buffer, k2_slot_list, writers, slot_size, sample_writer);
+                let mut writers = writers.lock().unwrap();
+                write_data_to_file(buffer, k2_slot_list, &mut writers, sample_writer.as_deref_mut());
             }
         },
     )
     .expect("failed");
 
+    let no_minimizers = no_minimizers.into_inner();
+    // 其他页组的 run 读的是同样的 reads, 只统计一次
+    if no_minimizers > 0 && !args.hits_only {
+        let reason = if args.minimum_quality_score > 0 {
+            "shorter than k or masked by --minimum-quality-score"
+        } else {
+            "shorter than k"
+        };
+        tally(
+            "splitr",
+            "reads_without_minimizers",
+            Some(file_index),
+            no_minimizers,
+            &format!(
+                "{} reads of sample {} have no minimizer ({}) and stay unclassified",
+                no_minimizers, file_index, reason
+            ),
+        );
+    }
+
     Ok(())
 }
 
+/// 写入 sample_dup_{file_index}.map, 每行为保留的 read 编号和它代表的 read 数
+fn write_duplicate_counts(
+    args: &Args,
+    file_index: usize,
+    duplicates: HashMap<u128, (usize, u64)>,
+) -> Result<()> {
+    let mut counts: Vec<(usize, u64)> = duplicates
+        .into_values()
+        .filter(|(_, count)| *count > 1)
+        .collect();
+    counts.sort_unstable();
+
+    let collapsed: u64 = counts.iter().map(|(_, count)| count - 1).sum();
+    println!(
+        "sample {}: collapsed {} duplicate reads into {} reads",
+        file_index,
+        collapsed,
+        counts.len()
+    );
+
+    let filename = args
+        .chunk_dir
+        .join(format!("sample_dup_{}.map", file_index));
+    let mut writer = BufWriter::new(File::create(filename)?);
+    for (index, count) in counts {
+        writeln!(writer, "{}\t{}", index, count)?;
+    }
+    writer.flush()
+}
+
 /// 处理样本文件
 fn process_files<F>(args: &Args, hash_config: HashConfig, mut action: F) -> Result<()>
 where
     F: FnMut(usize, OptionPair<PathBuf>) -> Result<()>,
 {
     let file_path = args.chunk_dir.join("sample_file.map");
-    let mut file_writer = create_sample_file(&file_path);
-    let mut file_index = get_lastest_file_index(&file_path)?;
+    // 只写 chunk 文件时沿用之前那次 run 的编号: classify 从空的 chunk 目录开始, 编号从 1 开始
+    let mut file_writer = (!args.hits_only).then(|| create_sample_file(&file_path));
+    let mut file_index = if args.hits_only {
+        0
+    } else {
+        get_lastest_file_index(&file_path)?
+    };
 
     let chunk_size = if args.paired_end_processing && !args.single_file_pairs {
         2
@@ -273,13 +612,15 @@ where
     for file_pair in files {
         file_index += 1;
         let path_pair = OptionPair::from_slice(file_pair);
-        writeln!(
-            file_writer,
-            "{}\t{}",
-            file_index,
-            path_pair.reduce_str(",", |a| a.to_str().unwrap().to_string())
-        )?;
-        file_writer.flush().unwrap();
+        if let Some(file_writer) = file_writer.as_mut() {
+            writeln!(
+                file_writer,
+                "{}\t{}",
+                file_index,
+                path_pair.reduce_str(",", |a| a.to_str().unwrap().to_string())
+            )?;
+            file_writer.flush().unwrap();
+        }
 
         action(file_index, path_pair)?;
     }
@@ -288,6 +629,16 @@ where
 }
 
 pub fn run(args: Args) -> Result<()> {
+    // classify 自己收集并写入 findings, 这里只处理单独运行的 splitr
+    if !args.audit {
+        return split(args);
+    }
+    enable_audit();
+    let chunk_dir = args.chunk_dir.clone();
+    with_findings(chunk_dir, || split(args))
+}
+
+fn split(args: Args) -> Result<()> {
     let args = args.process_input_files()?;
     let options_filename = &args.database.join("opts.k2d");
     let idx_opts = IndexOptions::read_index_options(options_filename)?;
@@ -318,17 +669,24 @@ pub fn run(args: Args) -> Result<()> {
     }
 
     let meros = idx_opts.as_meros();
+    check_disk_space(&args, &meros)?;
     let start = Instant::now();
     let partition = hash_config.partition;
     let mut writers: Vec<BufWriter<fs::File>> =
         init_chunk_writers(&args, partition, hash_config.hash_capacity);
 
-    process_files(&args, hash_config, |file_index, path_pair| {
-        let mut sample_writer =
-            create_sample_file(args.chunk_dir.join(format!("sample_id_{}.map", file_index)));
+    let result = process_files(&args, hash_config, |file_index, path_pair| {
+        let mut sample_writer = (!args.hits_only).then(|| {
+            create_sample_file(args.chunk_dir.join(format!("sample_id_{}.map", file_index)))
+        });
 
         let score = args.minimum_quality_score;
-        let mut reader = FastxReader::from_paths(path_pair, file_index, score)?;
+        let reader = Interruptible::new(FastxReader::from_paths(path_pair, file_index, score)?);
+        // 在去重之前统计, 重复的 read 也计入
+        let reader = MaskCounter::new(reader, score > 0 && !args.hits_only);
+        // 不去重时不跟踪任何 read
+        let max_reads = if args.dedup { args.dedup_max_reads } else { 0 };
+        let mut reader = DedupReader::new(reader, max_reads);
         process_fastx_file(
             &args,
             meros,
@@ -336,11 +694,50 @@ pub fn run(args: Args) -> Result<()> {
             file_index,
             &mut reader,
             &mut writers,
-            &mut sample_writer,
+            sample_writer.as_mut(),
         )
         .expect("process fastx file error");
+        // 读取出错时 read_parallel 直接结束, 剩下的 read 没有处理
+        if let (Some(error), false) = (reader.inner.inner.error(), args.hits_only) {
+            warn(
+                "splitr",
+                "truncated_reads",
+                Some(file_index),
+                &format!(
+                    "sample {} ends at a read error, the reads after it are skipped: {}",
+                    file_index, error
+                ),
+            );
+        }
+        let masked = &reader.inner;
+        if masked.reads > 0 {
+            tally(
+                "splitr",
+                "masked_bases",
+                Some(file_index),
+                masked.bases,
+                &format!(
+                    "{} bases in {} reads of sample {} are below --minimum-quality-score {} and masked",
+                    masked.bases, masked.reads, file_index, score
+                ),
+            );
+        }
+        if let Some(sample_writer) = sample_writer.as_mut() {
+            sample_writer.flush()?;
+        }
+        if args.dedup && !args.hits_only {
+            write_duplicate_counts(&args, file_index, reader.seen)?;
+        }
+        if interrupted().is_some() {
+            return Err(interrupted_error("splitr"));
+        }
         Ok(())
-    })?;
+    });
+    // 中断时也要把已处理的数据写入 chunk 文件
+    for writer in writers.iter_mut() {
+        writer.flush()?;
+    }
+    result?;
     let duration = start.elapsed();
     println!("splitr took: {:?}", duration);
 
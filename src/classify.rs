@@ -1,7 +1,7 @@
 use crate::compact_hash::Compact;
 use crate::readcounts::TaxonCounters;
 use crate::taxonomy::Taxonomy;
-use crate::HitGroup;
+use crate::{fmix64, HitGroup};
 use seqkmer::SpaceDist;
 use std::collections::HashMap;
 use std::sync::atomic::{AtomicUsize, Ordering};
@@ -93,10 +93,11 @@ fn stat_hits<'a>(
 
         *counts.entry(key).or_insert(0) += 1;
 
+        // HyperLogLog 直接用输入作哈希值, cell 值只有低 32 位, 需要先打散
         cur_taxon_counts
             .entry(key as u64)
             .or_default()
-            .add_kmer(value as u64);
+            .add_kmer(fmix64(value as u64));
 
         let ext_code = taxonomy.nodes[key as usize].external_id;
         let pos = row.kmer_id as usize;
@@ -167,3 +168,101 @@ pub fn process_hitgroup(
 
     (clasify.to_owned(), ext_call, hit_string, cur_taxon_counts)
 }
+
+/// Agreement of the calls of one read against two databases
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Agreement {
+    /// Both databases call the same taxon
+    Agree,
+    /// Both calls lie on the same lineage, e.g. a genus and one of its species
+    Compatible,
+    /// The calls lie on different lineages
+    Conflict,
+    /// Only the first database classifies the read
+    FirstOnly,
+    /// Only the second database classifies the read
+    SecondOnly,
+    /// Neither database classifies the read
+    Unclassified,
+}
+
+impl Agreement {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Agreement::Agree => "agree",
+            Agreement::Compatible => "compatible",
+            Agreement::Conflict => "conflict",
+            Agreement::FirstOnly => "db1_only",
+            Agreement::SecondOnly => "db2_only",
+            Agreement::Unclassified => "unclassified",
+        }
+    }
+}
+
+/// Compares the calls of one read against two databases.
+///
+/// When the databases share taxids (see [`Taxonomy::shares_taxids`]), the calls agree
+/// if their taxids are equal and are compatible if one is an ancestor of the other in
+/// either taxonomy. Otherwise, e.g. NCBI against GTDB, the calls are matched by
+/// scientific name, ignoring case, and are compatible if the name of one call appears
+/// on the lineage of the other.
+///
+/// # Arguments
+///
+/// * `taxonomy1` - The taxonomy of the first database.
+/// * `call1` - The external ID of the call against the first database, 0 if unclassified.
+/// * `taxonomy2` - The taxonomy of the second database.
+/// * `call2` - The external ID of the call against the second database, 0 if unclassified.
+/// * `shared_taxids` - Whether the two taxonomies share taxids.
+pub fn compare_calls(
+    taxonomy1: &Taxonomy,
+    call1: u64,
+    taxonomy2: &Taxonomy,
+    call2: u64,
+    shared_taxids: bool,
+) -> Agreement {
+    match (call1 > 0, call2 > 0) {
+        (false, false) => return Agreement::Unclassified,
+        (true, false) => return Agreement::FirstOnly,
+        (false, true) => return Agreement::SecondOnly,
+        (true, true) => {}
+    }
+
+    if shared_taxids {
+        if call1 == call2 {
+            return Agreement::Agree;
+        }
+        let on_lineage = |taxonomy: &Taxonomy| {
+            let a = taxonomy.get_internal_id(call1);
+            let b = taxonomy.get_internal_id(call2);
+            let lca = taxonomy.lca(a, b);
+            a != 0 && b != 0 && (lca == a || lca == b)
+        };
+        return if on_lineage(taxonomy1) || on_lineage(taxonomy2) {
+            Agreement::Compatible
+        } else {
+            Agreement::Conflict
+        };
+    }
+
+    let id1 = taxonomy1.get_internal_id(call1);
+    let id2 = taxonomy2.get_internal_id(call2);
+    let name1 = taxonomy1.name_of(id1);
+    let name2 = taxonomy2.name_of(id2);
+    if name1.eq_ignore_ascii_case(name2) {
+        return Agreement::Agree;
+    }
+
+    let on_lineage = |lineage: Vec<&str>, name: &str| {
+        lineage
+            .iter()
+            .any(|ancestor| ancestor.eq_ignore_ascii_case(name))
+    };
+    if on_lineage(taxonomy1.lineage_names(id1), name2)
+        || on_lineage(taxonomy2.lineage_names(id2), name1)
+    {
+        Agreement::Compatible
+    } else {
+        Agreement::Conflict
+    }
+}
@@ -1,9 +1,10 @@
 use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::cmp::Ordering as CmpOrdering;
+use std::collections::HashMap;
 use std::fmt::{self, Debug};
 use std::fs::File;
 use std::fs::OpenOptions;
-use std::io::{BufWriter, Read, Result, Write};
+use std::io::{BufWriter, Read, Result, Seek, SeekFrom, Write};
 use std::path::Path;
 
 /// Trait for compact hash operations
@@ -472,29 +473,87 @@ fn read_page_data(file: &mut File, data: &mut [u32]) -> Result<()> {
     Ok(())
 }
 
-fn read_page_from_file<P: AsRef<Path>>(filename: P) -> Result<Page> {
+/// Cells reserved behind the page data for the overflow block of the next page
+const OVERFLOW_RESERVE: usize = 1024;
+
+/// Allocates the data buffer of a page with `capacity` cells
+///
+/// With `huge_pages` the buffer is advised before it is filled, so the kernel
+/// backs it with transparent huge pages as the cells are first written, rather
+/// than leaving it to khugepaged to collapse the 4K pages later.
+fn alloc_page_data(capacity: usize, huge_pages: bool) -> Vec<u32> {
+    let mut data = Vec::with_capacity(capacity + OVERFLOW_RESERVE);
+    if huge_pages {
+        advise_huge_pages(&data);
+    }
+    data.resize(capacity, 0);
+    data
+}
+
+/// Advises the kernel to back the allocation of `data` with transparent huge pages
+///
+/// Only the 2MB-aligned part of the allocation can be promoted, so buffers smaller
+/// than one huge page are left as they are.
+#[cfg(target_os = "linux")]
+fn advise_huge_pages(data: &Vec<u32>) {
+    const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+    let addr = data.as_ptr() as usize;
+    let len = data.capacity() * std::mem::size_of::<u32>();
+    let start = (addr + HUGE_PAGE_SIZE - 1) & !(HUGE_PAGE_SIZE - 1);
+    let end = (addr + len) & !(HUGE_PAGE_SIZE - 1);
+    if end > start {
+        unsafe { libc::madvise(start as *mut libc::c_void, end - start, libc::MADV_HUGEPAGE) };
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn advise_huge_pages(_data: &Vec<u32>) {}
+
+/// Returns the amount of memory of this process backed by transparent huge pages in kB
+///
+/// Reads `AnonHugePages` from /proc/self/smaps_rollup, `None` if it is not available.
+#[cfg(target_os = "linux")]
+pub fn anon_huge_pages_kb() -> Option<u64> {
+    let smaps = std::fs::read_to_string("/proc/self/smaps_rollup").ok()?;
+    smaps
+        .lines()
+        .find_map(|line| line.strip_prefix("AnonHugePages:"))
+        .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn anon_huge_pages_kb() -> Option<u64> {
+    None
+}
+
+fn read_page_from_file<P: AsRef<Path>>(filename: P, huge_pages: bool) -> Result<Page> {
     let mut file = std::fs::File::open(filename)?;
     let (index, capacity) = read_page_metadata(&mut file)?;
-    let mut data = vec![0u32; capacity];
+    let mut data = alloc_page_data(capacity, huge_pages);
     read_page_data(&mut file, &mut data)?;
 
     Ok(Page::new(index, capacity, data))
 }
 
-fn read_large_page_from_file<P: AsRef<Path>>(large_page: &mut Page, filename: P) -> Result<()> {
+fn read_large_page_from_file<P: AsRef<Path>>(
+    large_page: &mut Page,
+    filename: P,
+    huge_pages: bool,
+) -> Result<()> {
     let mut file = File::open(filename)?;
 
     let (index, capacity) = read_page_metadata(&mut file)?;
 
     let current_len = large_page.data.capacity();
 
-    if capacity > current_len {
-        // If the capacity in the file is greater than the current page's capacity, extend the memory
-        large_page.data.resize(capacity, 0);
-    } else if capacity < current_len {
-        // If the capacity in the file is smaller than the current page's capacity, truncate and zero out the excess
+    if capacity > current_len || capacity + 2 * OVERFLOW_RESERVE < current_len {
+        // If the buffer is too small or much too large, allocate a new one instead of
+        // resizing the current one, so it can be advised before it is filled
+        large_page.data = alloc_page_data(capacity, huge_pages);
+    } else {
         large_page.data.truncate(capacity);
-        large_page.data.shrink_to_fit(); // Free up excess memory
+        large_page.data.resize(capacity, 0);
     }
 
     read_page_data(&mut file, &mut large_page.data)?;
@@ -510,10 +569,11 @@ pub fn read_next_page<P: AsRef<Path> + Debug>(
     hash_sorted_files: &Vec<P>,
     page_index: usize,
     config: HashConfig,
+    huge_pages: bool,
 ) -> Result<()> {
     let mut hash_file = &hash_sorted_files[page_index];
     let parition = config.partition;
-    read_large_page_from_file(large_page, hash_file)?;
+    read_large_page_from_file(large_page, hash_file, huge_pages)?;
 
     let next_page = if large_page.data.last().map_or(false, |&x| x != 0) {
         if config.version < 1 {
@@ -528,6 +588,174 @@ pub fn read_next_page<P: AsRef<Path> + Debug>(
     Ok(())
 }
 
+/// Appends the overflow block of a version 0 page to the page file itself
+///
+/// Version 0 pages continue linear probing into the first block (up to and including
+/// the first empty cell) of the next page. Storing that block at the end of the page
+/// makes the page self-contained: the page then ends with an empty cell, so both
+/// loaders stop there and the version 1 loader never falls back to the first block
+/// of the same file. Pages that already end with an empty cell are left untouched,
+/// which also makes the migration safe to repeat.
+///
+/// # Returns
+///
+/// The number of appended cells
+pub fn embed_overflow_block<P: AsRef<Path>>(hash_file: P, next_hash_file: P) -> Result<usize> {
+    let mut file = OpenOptions::new().read(true).write(true).open(&hash_file)?;
+    let (_, capacity) = read_page_metadata(&mut file)?;
+    if capacity == 0 {
+        return Ok(0);
+    }
+
+    let cell_size = std::mem::size_of::<u32>() as u64;
+    let data_end = 16 + capacity as u64 * cell_size;
+    let file_len = file.metadata()?.len();
+    if file_len < data_end {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{:?} is shorter than its page header", hash_file.as_ref()),
+        ));
+    }
+    // 丢弃上次迁移中断时写了一半的数据
+    if file_len > data_end {
+        file.set_len(data_end)?;
+    }
+    file.seek(SeekFrom::Start(data_end - cell_size))?;
+    if file.read_u32::<LittleEndian>()? == 0 {
+        return Ok(0);
+    }
+
+    let block = read_first_block_from_file(&next_hash_file)?;
+    let block = &block.data[..block.size];
+    if !matches!(block.last(), Some(0)) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{:?} has no empty cell", next_hash_file.as_ref()),
+        ));
+    }
+
+    let mut writer = BufWriter::new(&mut file);
+    for &cell in block {
+        writer.write_u32::<LittleEndian>(cell)?;
+    }
+    writer.flush()?;
+    drop(writer);
+    // 数据写完后再更新 capacity, 中断时页面仍保持原样
+    file.seek(SeekFrom::Start(8))?;
+    file.write_u64::<LittleEndian>((capacity + block.len()) as u64)?;
+    file.sync_all()?;
+
+    Ok(block.len())
+}
+
+/// Eviction policy of the `PageCache`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum EvictionPolicy {
+    /// Evict the least recently used page
+    Lru,
+    /// Evict pages that no remaining chunk file refers to first, then fall back to LRU
+    Schedule,
+}
+
+/// A bounded cache of loaded hash pages
+///
+/// Pages are kept in least to most recently used order. With
+/// `EvictionPolicy::Schedule` the cache needs to know the page index of every
+/// chunk file that is still to be processed, see `PageCache::schedule`.
+pub struct PageCache {
+    config: HashConfig,
+    capacity: usize,
+    policy: EvictionPolicy,
+    pages: Vec<(usize, Page)>,
+    remaining: HashMap<usize, usize>,
+    huge_pages: bool,
+}
+
+impl PageCache {
+    /// Creates a cache holding at most `capacity` pages (at least one)
+    ///
+    /// With `huge_pages` the page buffers are backed by transparent huge pages where possible.
+    pub fn new(
+        config: HashConfig,
+        capacity: usize,
+        policy: EvictionPolicy,
+        huge_pages: bool,
+    ) -> Self {
+        Self {
+            config,
+            capacity: capacity.max(1),
+            policy,
+            pages: Vec::new(),
+            remaining: HashMap::new(),
+            huge_pages,
+        }
+    }
+
+    pub fn config(&self) -> &HashConfig {
+        &self.config
+    }
+
+    /// Registers the page indices of the chunk files that are going to be processed
+    pub fn schedule<I: IntoIterator<Item = usize>>(&mut self, page_indices: I) {
+        for page_index in page_indices {
+            *self.remaining.entry(page_index).or_insert(0) += 1;
+        }
+    }
+
+    /// Number of scheduled uses left for a page
+    pub fn remaining(&self, page_index: usize) -> usize {
+        self.remaining.get(&page_index).cloned().unwrap_or(0)
+    }
+
+    fn victim(&self) -> usize {
+        match self.policy {
+            EvictionPolicy::Lru => 0,
+            EvictionPolicy::Schedule => self
+                .pages
+                .iter()
+                .position(|(page_index, _)| self.remaining(*page_index) == 0)
+                .unwrap_or(0),
+        }
+    }
+
+    /// Returns the page for `page_index`, loading it from `hash_sorted_files` if it is not cached
+    ///
+    /// The returned flag is `true` if the page had to be loaded.
+    pub fn get<P: AsRef<Path> + Debug>(
+        &mut self,
+        hash_sorted_files: &Vec<P>,
+        page_index: usize,
+    ) -> Result<(&Page, bool)> {
+        if let Some(count) = self.remaining.get_mut(&page_index) {
+            *count = count.saturating_sub(1);
+        }
+
+        if let Some(pos) = self.pages.iter().position(|(idx, _)| *idx == page_index) {
+            let entry = self.pages.remove(pos);
+            self.pages.push(entry);
+            return Ok((&self.pages[self.pages.len() - 1].1, false));
+        }
+
+        // Reuse the buffer of the evicted page to avoid reallocating a whole page
+        let mut page = if self.pages.len() >= self.capacity {
+            let victim = self.victim();
+            self.pages.remove(victim).1
+        } else {
+            Page::default()
+        };
+        read_next_page(
+            &mut page,
+            hash_sorted_files,
+            page_index,
+            self.config,
+            self.huge_pages,
+        )?;
+        self.pages.push((page_index, page));
+
+        Ok((&self.pages[self.pages.len() - 1].1, true))
+    }
+}
+
 #[derive(Clone)]
 pub struct Page {
     pub index: usize,
@@ -604,25 +832,30 @@ pub struct CHTable {
 }
 
 impl CHTable {
+    /// Loads all pages, see `CHTable::from_range`
     pub fn from_hash_files<P: AsRef<Path> + Debug>(
         config: HashConfig,
         hash_sorted_files: &Vec<P>,
+        huge_pages: bool,
     ) -> Result<CHTable> {
         let end = hash_sorted_files.len();
-        Self::from_range(config, hash_sorted_files, 0, end)
+        Self::from_range(config, hash_sorted_files, 0, end, huge_pages)
     }
 
+    /// Loads the pages `start..end`, backing them with transparent huge pages
+    /// where possible if `huge_pages` is set
     pub fn from_range<P: AsRef<Path> + Debug>(
         config: HashConfig,
         hash_sorted_files: &Vec<P>,
         start: usize,
         end: usize,
+        huge_pages: bool,
     ) -> Result<CHTable> {
         let mut pages = vec![Page::default(); start];
         let parition = hash_sorted_files.len();
         for i in start..end {
             let mut hash_file = &hash_sorted_files[i];
-            let mut page = read_page_from_file(&hash_file)?;
+            let mut page = read_page_from_file(&hash_file, huge_pages)?;
             let next_page = if page.data.last().map_or(false, |&x| x != 0) {
                 if config.version < 1 {
                     hash_file = &hash_sorted_files[(i + 1) % parition]
@@ -0,0 +1,155 @@
+use crate::taxonomy::Taxonomy;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Result, Write};
+use std::path::Path;
+
+/// Sample sheet column marking negative-control samples (`true`, `yes` or `1`)
+pub const NEGATIVE_CONTROL_COLUMN: &str = "negative_control";
+
+/// Whether a sample sheet value marks a negative control
+pub fn is_negative_control(value: &str) -> bool {
+    matches!(
+        value.trim().to_ascii_lowercase().as_str(),
+        "true" | "yes" | "1"
+    )
+}
+
+/// A taxon of a sample that is likely reagent or kit contamination
+#[derive(Debug, Clone, PartialEq)]
+pub struct Contaminant {
+    /// Internal taxon id
+    pub taxid: u64,
+    pub reads: u64,
+    /// Share of the classified reads of the sample
+    pub sample_frequency: f64,
+    /// Share of the classified reads of all negative controls
+    pub control_frequency: f64,
+    /// Mean reads per negative control
+    pub control_reads: f64,
+}
+
+/// Pooled read counts of the negative controls of a batch
+///
+/// A taxon of a sample is a likely contaminant when its share of the classified
+/// reads is not clearly above its share in the controls: contamination makes up
+/// a similar fraction of a low-biomass sample as of a blank, while genuine taxa
+/// are enriched in the samples. This only compares read shares against the
+/// pooled controls; it is not decontam's frequency method, which correlates a
+/// taxon's frequency with the input DNA concentration across samples.
+/// Subtraction removes the reads a taxon makes up per sequenced read in the
+/// controls, scaled to the library size of the sample.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::contamination::ControlProfile;
+/// use std::collections::HashMap;
+///
+/// let mut controls = ControlProfile::default();
+/// controls.add_sample(&HashMap::from([(7, 80), (9, 20)]), 1_000);
+/// controls.add_sample(&HashMap::from([(7, 40)]), 1_000);
+///
+/// // taxon 7 is half of the sample but 85.7% of the controls, taxon 9 is 2.8x enriched
+/// let sample = HashMap::from([(7, 500), (9, 400), (11, 100)]);
+/// let contaminants = controls.likely_contaminants(&sample, 2.0);
+/// assert_eq!(contaminants.len(), 1);
+/// assert_eq!(contaminants[0].taxid, 7);
+/// assert_eq!(contaminants[0].control_reads, 60.0);
+/// // taxon 9 is 1% of the control reads, 1% of a sample twice as deep is 20 reads
+/// assert_eq!(controls.expected_reads(9, 2_000), 20);
+/// assert_eq!(controls.expected_reads(9, 500), 5);
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct ControlProfile {
+    reads: HashMap<u64, u64>,
+    classified: u64,
+    /// Sequenced reads of the controls, classified or not
+    sequences: u64,
+    samples: usize,
+}
+
+impl ControlProfile {
+    /// Adds the reads per taxon (internal id) and the library size (all sequenced
+    /// reads) of one negative control
+    pub fn add_sample(&mut self, call_counts: &HashMap<u64, u64>, sequences: u64) {
+        for (&taxid, &reads) in call_counts {
+            *self.reads.entry(taxid).or_insert(0) += reads;
+            self.classified += reads;
+        }
+        self.sequences += sequences;
+        self.samples += 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples == 0
+    }
+
+    /// Reads of the taxon expected from contamination in a library of `sequences` reads,
+    /// its reads per sequenced read in the controls scaled to the library size, rounded
+    pub fn expected_reads(&self, taxid: u64, sequences: u64) -> u64 {
+        match self.sequences {
+            0 => 0,
+            n => {
+                let reads = *self.reads.get(&taxid).unwrap_or(&0) as f64;
+                (reads * sequences as f64 / n as f64).round() as u64
+            }
+        }
+    }
+
+    /// Taxa of a sample whose share is at most `fold` times their share in the controls,
+    /// most reads first
+    pub fn likely_contaminants(
+        &self,
+        call_counts: &HashMap<u64, u64>,
+        fold: f64,
+    ) -> Vec<Contaminant> {
+        let classified: u64 = call_counts.values().sum();
+        if classified == 0 || self.classified == 0 {
+            return Vec::new();
+        }
+        let mut contaminants: Vec<Contaminant> = call_counts
+            .iter()
+            .filter_map(|(&taxid, &reads)| {
+                let control_reads = *self.reads.get(&taxid)?;
+                let sample_frequency = reads as f64 / classified as f64;
+                let control_frequency = control_reads as f64 / self.classified as f64;
+                (reads > 0 && sample_frequency <= fold * control_frequency).then(|| Contaminant {
+                    taxid,
+                    reads,
+                    sample_frequency,
+                    control_frequency,
+                    control_reads: control_reads as f64 / self.samples as f64,
+                })
+            })
+            .collect();
+        contaminants.sort_by(|a, b| b.reads.cmp(&a.reads).then(a.taxid.cmp(&b.taxid)));
+        contaminants
+    }
+}
+
+/// Writes the likely contaminants of a sample as a tab separated table
+pub fn write_contaminants<P: AsRef<Path>>(
+    filename: P,
+    contaminants: &[Contaminant],
+    taxonomy: &Taxonomy,
+) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(filename)?);
+    writeln!(
+        writer,
+        "taxid\tname\treads\tsample_frequency\tcontrol_frequency\tcontrol_reads"
+    )?;
+    for contaminant in contaminants {
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{:.6}\t{:.6}\t{:.1}",
+            taxonomy.nodes[contaminant.taxid as usize].external_id,
+            taxonomy.name_of(contaminant.taxid as u32),
+            contaminant.reads,
+            contaminant.sample_frequency,
+            contaminant.control_frequency,
+            contaminant.control_reads,
+        )?;
+    }
+    writer.flush()
+}
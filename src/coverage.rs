@@ -0,0 +1,106 @@
+use crate::report::get_clade_counts;
+use crate::taxonomy::Taxonomy;
+use crate::utils::open_file;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Result, Write};
+use std::path::Path;
+
+/// Reference minimizer table written into the database directory by the build
+///
+/// One `taxid<TAB>minimizers` line per taxon holding cells of the hash table,
+/// the minimizers being those whose LCA in the library is exactly that taxon.
+pub const TAXON_MINIMIZERS_FILENAME: &str = "taxon_minimizers.tsv";
+
+/// Reads a reference minimizer table (`taxid<TAB>minimizers`), keyed by external taxid
+pub fn read_taxon_minimizers<P: AsRef<Path>>(filename: P) -> Result<HashMap<u64, u64>> {
+    let reader = BufReader::new(open_file(filename)?);
+    let mut minimizers = HashMap::new();
+    for line in reader.lines() {
+        let line = line?;
+        if let Some((taxid, count)) = line.trim().split_once('\t') {
+            if let (Ok(taxid), Ok(count)) = (taxid.parse::<u64>(), count.parse::<u64>()) {
+                minimizers.insert(taxid, count);
+            }
+        }
+    }
+    Ok(minimizers)
+}
+
+/// Writes a reference minimizer table, sorted by taxid
+pub fn write_taxon_minimizers<P: AsRef<Path>>(
+    filename: P,
+    minimizers: &HashMap<u64, u64>,
+) -> Result<()> {
+    let mut minimizers: Vec<(&u64, &u64)> = minimizers.iter().collect();
+    minimizers.sort_unstable();
+    let mut writer = BufWriter::new(File::create(filename)?);
+    for (taxid, count) in minimizers {
+        writeln!(writer, "{}\t{}", taxid, count)?;
+    }
+    writer.flush()
+}
+
+/// Reference minimizers of every clade, keyed by external taxid
+///
+/// The minimizer space of a clade is that of its own taxon plus all taxa below
+/// it, the same set the distinct minimizer count of a report clade is drawn from.
+pub fn clade_minimizers(taxonomy: &Taxonomy, minimizers: &HashMap<u64, u64>) -> HashMap<u64, u64> {
+    let mut internal: HashMap<u64, u64> = HashMap::new();
+    for (&external_id, &count) in minimizers {
+        let taxid = taxonomy.get_internal_id(external_id) as u64;
+        if taxid != 0 {
+            *internal.entry(taxid).or_insert(0) += count;
+        }
+    }
+    get_clade_counts(taxonomy, &internal)
+        .into_iter()
+        .map(|(taxid, count)| (taxonomy.nodes[taxid as usize].external_id, count))
+        .collect()
+}
+
+/// Loads a reference minimizer table and sums it up per clade, see [`clade_minimizers`]
+pub fn load_clade_minimizers<P: AsRef<Path>>(
+    filename: P,
+    taxonomy: &Taxonomy,
+) -> Result<HashMap<u64, u64>> {
+    let filename = filename.as_ref();
+    if !filename.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "reference minimizer table {:?} not found, rebuild the database or pass --taxon-minimizers",
+                filename
+            ),
+        ));
+    }
+    let minimizers = read_taxon_minimizers(filename)?;
+    Ok(clade_minimizers(taxonomy, &minimizers))
+}
+
+/// Fraction of the reference minimizers of a clade observed in a sample
+///
+/// `observed` is the distinct minimizer count of the clade in the sample and
+/// `reference` the number of minimizers of the clade in the database. Both are
+/// counts: the database keeps no minimizer positions, so this is not the
+/// fraction of the genome covered, though it tracks it when minimizers are
+/// spread evenly along the genomes. Distinct minimizer counts are HyperLogLog
+/// estimates, so the result is capped at 1. A clade without reference
+/// minimizers has fraction 0.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::coverage::minimizer_fraction;
+///
+/// assert_eq!(minimizer_fraction(250, 1000), 0.25);
+/// assert_eq!(minimizer_fraction(1010, 1000), 1.0);
+/// assert_eq!(minimizer_fraction(5, 0), 0.0);
+/// ```
+pub fn minimizer_fraction(observed: u64, reference: u64) -> f64 {
+    if reference == 0 {
+        0.0
+    } else {
+        (observed as f64 / reference as f64).min(1.0)
+    }
+}
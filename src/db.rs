@@ -1,3 +1,4 @@
+use crate::audit::warn;
 use crate::compact_hash::{Compact, HashConfig, Slot};
 // use crate::mmscanner::MinimizerScanner;
 use crate::taxonomy::{NCBITaxonomy, Taxonomy};
@@ -112,6 +113,7 @@ fn write_hashtable_to_file(
 /// * `taxonomy` - The taxonomy used for processing
 /// * `page_size` - The size of each page
 /// * `page_index` - The index of the current page
+/// * `taxon_cells` - Filled cells of the page are added to this count per internal taxon ID
 ///
 /// # Returns
 ///
@@ -123,6 +125,7 @@ pub fn process_k2file(
     taxonomy: &Taxonomy,
     page_size: usize,
     page_index: usize,
+    taxon_cells: &mut HashMap<u32, u64>,
 ) -> IOResult<usize> {
     let total_counter = AtomicUsize::new(0);
 
@@ -161,6 +164,13 @@ pub fn process_k2file(
         total_counter.fetch_add(cells.len(), Ordering::SeqCst);
     }
 
+    for cell in &page {
+        let taxid = cell.load(Ordering::Relaxed).right(value_mask).to_u32();
+        if taxid != 0 {
+            *taxon_cells.entry(taxid).or_insert(0) += 1;
+        }
+    }
+
     let size_count =
         write_hashtable_to_file(&page, &page_file, page_index as u64, capacity as u64)?;
     Ok(size_count)
@@ -257,22 +267,45 @@ pub fn convert_fna_to_k2_format<P: AsRef<Path>>(
 
             for record in seqs {
                 let header = &record.header;
-                record.body.apply_mut(|m_iter| {
-                    if let Some(ext_taxid) = id_to_taxon_map.get(&header.id) {
-                        let taxid = taxonomy.get_internal_id(*ext_taxid);
-                        let k2_cell: Vec<(usize, Slot<u32>)> = m_iter
-                            .map(|(_, hash_key)| {
-                                let index: usize = hash_config.index(hash_key);
-                                let idx = index % chunk_size;
-                                let partition_index = index / chunk_size;
-                                let cell =
-                                    Slot::new(idx, u32::hash_value(hash_key, value_bits, taxid));
-                                (partition_index, cell)
-                            })
-                            .collect();
-
-                        k2_cell_list.extend_from_slice(&k2_cell);
+                let ext_taxid = match id_to_taxon_map.get(&header.id) {
+                    Some(ext_taxid) => *ext_taxid,
+                    None => {
+                        warn(
+                            "build",
+                            "missing_seqid_taxid",
+                            None,
+                            &format!(
+                                "sequence {} has no taxid in seqid2taxid.map, skipped",
+                                header.id
+                            ),
+                        );
+                        continue;
                     }
+                };
+                let taxid = taxonomy.get_internal_id(ext_taxid);
+                if taxid == 0 {
+                    warn(
+                        "build",
+                        "unknown_taxid",
+                        None,
+                        &format!(
+                            "taxid {} of sequence {} is not in the taxonomy, its minimizers are stored as taxid 0",
+                            ext_taxid, header.id
+                        ),
+                    );
+                }
+                record.body.apply_mut(|m_iter| {
+                    let k2_cell: Vec<(usize, Slot<u32>)> = m_iter
+                        .map(|(_, hash_key)| {
+                            let index: usize = hash_config.index(hash_key);
+                            let idx = index % chunk_size;
+                            let partition_index = index / chunk_size;
+                            let cell = Slot::new(idx, u32::hash_value(hash_key, value_bits, taxid));
+                            (partition_index, cell)
+                        })
+                        .collect();
+
+                    k2_cell_list.extend_from_slice(&k2_cell);
                 });
             }
 
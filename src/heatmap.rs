@@ -0,0 +1,270 @@
+use crate::compact_hash::Compact;
+use crate::taxonomy::Taxonomy;
+use crate::HitGroup;
+use dashmap::DashMap;
+use flate2::Crc;
+use seqkmer::OptionPair;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Result, Write};
+use std::path::Path;
+
+/// Output format of the hit heatmap
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum HeatmapFormat {
+    /// Tab separated table, one row per taxon
+    Tsv,
+    /// NumPy `.npz` archive with the arrays `taxids`, `names` and `counts`
+    Npz,
+}
+
+impl HeatmapFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            HeatmapFormat::Tsv => "tsv",
+            HeatmapFormat::Npz => "npz",
+        }
+    }
+}
+
+/// Returns the bin of a minimizer position within a read
+///
+/// `pos` is the 1-based minimizer position as stored in `Row::kmer_id`, the read
+/// (or mate) covers the positions `(start, end]`.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::heatmap::position_bin;
+///
+/// assert_eq!(position_bin(1, 0, 100, 10), 0);
+/// assert_eq!(position_bin(100, 0, 100, 10), 9);
+/// // second mate of a pair, positions 101..=150
+/// assert_eq!(position_bin(126, 100, 150, 10), 5);
+/// ```
+pub fn position_bin(pos: usize, start: usize, end: usize, bins: usize) -> usize {
+    let size = end.saturating_sub(start).max(1);
+    let offset = pos.saturating_sub(start + 1).min(size - 1);
+    offset * bins / size
+}
+
+/// Minimizer hits per taxon and relative read position of one sample
+///
+/// Every hit counts for the taxon its minimizer is assigned to in the database,
+/// independent of the final call of the read. Positions are binned relative to
+/// the length of the read (each mate of a pair on its own), so hits piling up in
+/// the first or last bins point to adapters or primers, hits in a few inner bins
+/// to conserved regions.
+pub struct HitHeatmap {
+    bins: usize,
+    counts: DashMap<u32, Vec<u64>>,
+}
+
+impl HitHeatmap {
+    pub fn new(bins: usize) -> Self {
+        Self {
+            bins: bins.max(1),
+            counts: DashMap::new(),
+        }
+    }
+
+    /// Adds the minimizer hits of one read, counted `multiplicity` times for collapsed duplicates
+    pub fn add_hits(&self, hits: &HitGroup, value_mask: usize, multiplicity: u64) {
+        let mut read_counts: HashMap<u32, Vec<u64>> = HashMap::new();
+        for row in &hits.rows {
+            let taxid = row.value.right(value_mask);
+            let pos = row.kmer_id as usize;
+            let (start, end) = match hits.range {
+                OptionPair::Single(range) => range,
+                OptionPair::Pair(first, _) if pos <= first.1 => first,
+                OptionPair::Pair(_, second) => second,
+            };
+            let bin = position_bin(pos, start, end, self.bins);
+            read_counts
+                .entry(taxid)
+                .or_insert_with(|| vec![0; self.bins])[bin] += multiplicity;
+        }
+
+        for (taxid, counts) in read_counts {
+            let mut entry = self
+                .counts
+                .entry(taxid)
+                .or_insert_with(|| vec![0; self.bins]);
+            for (total, count) in entry.iter_mut().zip(counts) {
+                *total += count;
+            }
+        }
+    }
+
+    /// Rows of the heatmap as (external taxid, name, counts), most hits first
+    fn rows<'a>(&self, taxonomy: &'a Taxonomy) -> Vec<(u64, &'a str, Vec<u64>)> {
+        let mut rows: Vec<(u64, &str, Vec<u64>)> = self
+            .counts
+            .iter()
+            .map(|entry| {
+                let internal_id = *entry.key();
+                (
+                    taxonomy.nodes[internal_id as usize].external_id,
+                    taxonomy.name_of(internal_id),
+                    entry.value().clone(),
+                )
+            })
+            .collect();
+        rows.sort_by(|a, b| {
+            let total = |counts: &Vec<u64>| counts.iter().sum::<u64>();
+            total(&b.2).cmp(&total(&a.2)).then(a.0.cmp(&b.0))
+        });
+        rows
+    }
+
+    /// Writes the heatmap to `filename` in the given format
+    pub fn write<P: AsRef<Path>>(
+        &self,
+        filename: P,
+        format: HeatmapFormat,
+        taxonomy: &Taxonomy,
+    ) -> Result<()> {
+        let rows = self.rows(taxonomy);
+        let mut writer = BufWriter::new(File::create(filename)?);
+        match format {
+            HeatmapFormat::Tsv => {
+                write!(writer, "taxid\tname\ttotal")?;
+                for bin in 0..self.bins {
+                    write!(writer, "\tbin_{}", bin)?;
+                }
+                writeln!(writer)?;
+                for (taxid, name, counts) in &rows {
+                    write!(
+                        writer,
+                        "{}\t{}\t{}",
+                        taxid,
+                        name,
+                        counts.iter().sum::<u64>()
+                    )?;
+                    for count in counts {
+                        write!(writer, "\t{}", count)?;
+                    }
+                    writeln!(writer)?;
+                }
+            }
+            HeatmapFormat::Npz => {
+                let taxids: Vec<u64> = rows.iter().map(|row| row.0).collect();
+                let names: Vec<&str> = rows.iter().map(|row| row.1).collect();
+                let counts: Vec<u64> = rows.iter().flat_map(|row| row.2.clone()).collect();
+                write_npz(
+                    &mut writer,
+                    &[
+                        ("taxids.npy", npy_u64(&taxids, &[taxids.len()])),
+                        ("names.npy", npy_str(&names)),
+                        ("counts.npy", npy_u64(&counts, &[rows.len(), self.bins])),
+                    ],
+                )?;
+            }
+        }
+        writer.flush()
+    }
+}
+
+/// Serializes an array in the `.npy` format (version 1.0)
+fn npy(descr: &str, shape: &[usize], data: Vec<u8>) -> Vec<u8> {
+    let shape = match shape {
+        [n] => format!("({},)", n),
+        _ => format!(
+            "({})",
+            shape
+                .iter()
+                .map(|n| n.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let mut header = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+        descr, shape
+    );
+    // magic (6) + version (2) + header length (2) + header 按 64 字节对齐
+    let padding = 63 - (10 + header.len()) % 64;
+    header.push_str(&" ".repeat(padding));
+    header.push('\n');
+
+    let mut buffer = Vec::with_capacity(10 + header.len() + data.len());
+    buffer.extend_from_slice(b"\x93NUMPY\x01\x00");
+    buffer.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    buffer.extend_from_slice(header.as_bytes());
+    buffer.extend(data);
+    buffer
+}
+
+fn npy_u64(values: &[u64], shape: &[usize]) -> Vec<u8> {
+    let data = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+    npy("<u8", shape, data)
+}
+
+/// Fixed width unicode array (`<U{n}`), stored as UTF-32
+fn npy_str(values: &[&str]) -> Vec<u8> {
+    let width = values
+        .iter()
+        .map(|v| v.chars().count())
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let mut data = Vec::with_capacity(values.len() * width * 4);
+    for value in values {
+        let mut chars = value.chars().map(|c| c as u32).collect::<Vec<_>>();
+        chars.resize(width, 0);
+        data.extend(chars.iter().flat_map(|c| c.to_le_bytes()));
+    }
+    npy(&format!("<U{}", width), &[values.len()], data)
+}
+
+/// Writes the files as an uncompressed zip archive, which is what `numpy.savez` produces
+fn write_npz<W: Write>(writer: &mut W, files: &[(&str, Vec<u8>)]) -> Result<()> {
+    let too_large = || io::Error::new(io::ErrorKind::InvalidData, "heatmap too large for npz");
+    let mut central_directory = Vec::new();
+    let mut offset: u32 = 0;
+    for (name, data) in files {
+        let mut crc = Crc::new();
+        crc.update(data);
+        let size = u32::try_from(data.len()).map_err(|_| too_large())?;
+
+        // version, flags, method (stored), time, date (1980-01-01), crc, sizes, name length
+        let mut fields = Vec::new();
+        fields.extend_from_slice(&20u16.to_le_bytes());
+        fields.extend_from_slice(&0u16.to_le_bytes());
+        fields.extend_from_slice(&0u16.to_le_bytes());
+        fields.extend_from_slice(&0u16.to_le_bytes());
+        fields.extend_from_slice(&0x21u16.to_le_bytes());
+        fields.extend_from_slice(&crc.sum().to_le_bytes());
+        fields.extend_from_slice(&size.to_le_bytes());
+        fields.extend_from_slice(&size.to_le_bytes());
+        fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        fields.extend_from_slice(&0u16.to_le_bytes());
+
+        writer.write_all(&0x04034b50u32.to_le_bytes())?;
+        writer.write_all(&fields)?;
+        writer.write_all(name.as_bytes())?;
+        writer.write_all(data)?;
+
+        central_directory.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        central_directory.extend_from_slice(&20u16.to_le_bytes());
+        central_directory.extend_from_slice(&fields);
+        // comment length, disk, internal and external attributes, local header offset
+        central_directory.extend_from_slice(&[0u8; 10]);
+        central_directory.extend_from_slice(&offset.to_le_bytes());
+        central_directory.extend_from_slice(name.as_bytes());
+
+        offset = offset
+            .checked_add(30 + name.len() as u32 + size)
+            .ok_or_else(too_large)?;
+    }
+
+    writer.write_all(&central_directory)?;
+    writer.write_all(&0x06054b50u32.to_le_bytes())?;
+    writer.write_all(&[0u8; 4])?;
+    writer.write_all(&(files.len() as u16).to_le_bytes())?;
+    writer.write_all(&(files.len() as u16).to_le_bytes())?;
+    writer.write_all(&(central_directory.len() as u32).to_le_bytes())?;
+    writer.write_all(&offset.to_le_bytes())?;
+    writer.write_all(&0u16.to_le_bytes())?;
+    Ok(())
+}
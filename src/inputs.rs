@@ -0,0 +1,291 @@
+use regex::Regex;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Component, Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Default pattern to find the mate number in the file name of paired reads
+///
+/// Matches `_1`/`_2`, `_R1`/`_R2` and `.R1`/`.R2` right before the extension,
+/// optionally followed by the `_001` lane suffix of Illumina file names.
+pub const DEFAULT_PAIR_REGEX: &str = r"[._]R?([12])(?:_001)?\.[^.]+(?:\.gz)?$";
+
+/// Default pattern to find the barcode of demultiplexed Oxford Nanopore reads in their path
+///
+/// Matches the `barcodeNN` and `unclassified` directories and file name parts
+/// written by MinKNOW, Guppy and Dorado.
+pub const DEFAULT_BARCODE_REGEX: &str = r"(barcode\d+|unclassified)";
+
+/// File name suffixes of FASTA/FASTQ files picked up from directories
+const READ_FILE_SUFFIXES: [&str; 7] = [".fa", ".fasta", ".fna", ".fq", ".fastq", ".fas", ".seq"];
+
+/// Whether the path contains glob wildcards (`*`, `?` or `[...]`)
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::inputs::is_glob_pattern;
+///
+/// assert!(is_glob_pattern("runs/*/reads_R?.fq.gz"));
+/// assert!(!is_glob_pattern("runs/reads_R1.fq.gz"));
+/// ```
+pub fn is_glob_pattern(path: &str) -> bool {
+    path.contains(['*', '?', '['])
+}
+
+fn is_read_file(path: &Path) -> bool {
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name.to_ascii_lowercase(),
+        None => return false,
+    };
+    let name = name.strip_suffix(".gz").unwrap_or(&name);
+    READ_FILE_SUFFIXES
+        .iter()
+        .any(|suffix| name.ends_with(suffix))
+}
+
+/// Translates a glob pattern into an anchored regular expression
+///
+/// `*` and `?` do not match `/`, `**` matches any number of directories.
+fn glob_to_regex(pattern: &str) -> Result<Regex> {
+    let mut regex = String::from("^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                regex.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    regex.push('^');
+                }
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    if c == '\\' || c == '[' {
+                        regex.push('\\');
+                    }
+                    regex.push(c);
+                }
+                regex.push(']');
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex).map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+}
+
+/// Files matching a glob pattern, sorted
+fn expand_glob(pattern: &str) -> Result<Vec<PathBuf>> {
+    // 从第一个含通配符的路径组件之前的目录开始遍历
+    let mut base = PathBuf::new();
+    let mut depth = 0;
+    let mut recursive = false;
+    for component in Path::new(pattern).components() {
+        let part = component.as_os_str().to_string_lossy();
+        if depth == 0 && !is_glob_pattern(&part) {
+            base.push(component);
+        } else {
+            depth += 1;
+            recursive |= part.contains("**");
+        }
+    }
+    if base.as_os_str().is_empty() {
+        base.push(Component::CurDir);
+    }
+
+    let regex = glob_to_regex(pattern.trim_start_matches("./"))?;
+    let walker = WalkDir::new(&base).min_depth(1).follow_links(true);
+    let walker = if recursive {
+        walker
+    } else {
+        walker.max_depth(depth)
+    };
+    let mut files: Vec<PathBuf> = walker
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.into_path())
+        .filter(|path| {
+            let path = path.to_string_lossy();
+            regex.is_match(path.trim_start_matches("./"))
+        })
+        .collect();
+    files.sort_unstable();
+    Ok(files)
+}
+
+/// Expands directories and glob patterns among the inputs into read files
+///
+/// Directories are searched recursively for FASTA/FASTQ files (optionally gzip
+/// compressed), glob patterns are matched against all files. Symbolic links are
+/// followed in both. Other inputs are kept as they are. Returns the expanded list and whether any input was expanded.
+pub fn expand_input_files(inputs: &[PathBuf]) -> Result<(Vec<PathBuf>, bool)> {
+    let mut files = Vec::new();
+    let mut expanded = false;
+    for input in inputs {
+        let pattern = input.to_string_lossy();
+        let matches = if input.is_dir() {
+            let mut matches: Vec<PathBuf> = WalkDir::new(input)
+                .follow_links(true)
+                .into_iter()
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_type().is_file() && is_read_file(entry.path()))
+                .map(|entry| entry.into_path())
+                .collect();
+            matches.sort_unstable();
+            matches
+        } else if !input.exists() && is_glob_pattern(&pattern) {
+            expand_glob(&pattern)?
+        } else {
+            files.push(input.clone());
+            continue;
+        };
+
+        if matches.is_empty() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("no read files found for {}", pattern),
+            ));
+        }
+        expanded = true;
+        files.extend(matches);
+    }
+    Ok((files, expanded))
+}
+
+/// Orders paired read files as consecutive mate 1 / mate 2 pairs
+///
+/// `pair_regex` must have one capture group matching the mate number (`1` or `2`)
+/// in the file name. Files whose names only differ in that group form a pair.
+/// Pairs are returned in the order their first file appears.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::inputs::{pair_read_files, DEFAULT_PAIR_REGEX};
+/// use std::path::PathBuf;
+///
+/// let files: Vec<PathBuf> = [
+///     "run/b_R2_001.fastq.gz",
+///     "run/a_2.fq",
+///     "run/a_1.fq",
+///     "run/b_R1_001.fastq.gz",
+/// ]
+/// .iter()
+/// .map(PathBuf::from)
+/// .collect();
+/// let pairs = pair_read_files(&files, DEFAULT_PAIR_REGEX).unwrap();
+/// assert_eq!(
+///     pairs,
+///     ["run/b_R1_001.fastq.gz", "run/b_R2_001.fastq.gz", "run/a_1.fq", "run/a_2.fq"]
+///         .iter()
+///         .map(PathBuf::from)
+///         .collect::<Vec<_>>()
+/// );
+///
+/// assert!(pair_read_files(&files[..3], DEFAULT_PAIR_REGEX).is_err());
+/// ```
+pub fn pair_read_files(files: &[PathBuf], pair_regex: &str) -> Result<Vec<PathBuf>> {
+    let regex = Regex::new(pair_regex).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    if regex.captures_len() < 2 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "pair regex {} has no capture group for the mate",
+                pair_regex
+            ),
+        ));
+    }
+
+    let mut order: Vec<(PathBuf, String)> = Vec::new();
+    let mut mates: HashMap<(PathBuf, String), [Option<PathBuf>; 2]> = HashMap::new();
+    for file in files {
+        let name = file
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+        let mate = regex.captures(name).and_then(|caps| caps.get(1));
+        let (mate, key) = match mate {
+            Some(m) if m.as_str() == "1" || m.as_str() == "2" => (
+                m.as_str().parse::<usize>().unwrap() - 1,
+                format!("{}{}", &name[..m.start()], &name[m.end()..]),
+            ),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("can't find the mate number of {:?}", file),
+                ))
+            }
+        };
+
+        let key = (file.parent().unwrap_or(Path::new("")).to_path_buf(), key);
+        let slots = mates.entry(key.clone()).or_insert_with(|| {
+            order.push(key.clone());
+            [None, None]
+        });
+        if slots[mate].replace(file.clone()).is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("more than one mate {} file for {:?}", mate + 1, file),
+            ));
+        }
+    }
+
+    let mut pairs = Vec::with_capacity(files.len());
+    for key in order {
+        match mates.remove(&key).unwrap() {
+            [Some(first), Some(second)] => {
+                pairs.push(first);
+                pairs.push(second);
+            }
+            [Some(file), None] | [None, Some(file)] => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{:?} has no mate", file),
+                ))
+            }
+            [None, None] => unreachable!(),
+        }
+    }
+    Ok(pairs)
+}
+
+/// Returns the barcode of a read file, the last match of `regex` in its path
+///
+/// The first capture group is used if the regex has one, else the whole match.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::inputs::{barcode_of, DEFAULT_BARCODE_REGEX};
+/// use regex::Regex;
+/// use std::path::Path;
+///
+/// let regex = Regex::new(DEFAULT_BARCODE_REGEX).unwrap();
+/// let path = Path::new("run/fastq_pass/barcode07/FAL123_pass_barcode07_0.fastq.gz");
+/// assert_eq!(barcode_of(path, &regex).as_deref(), Some("barcode07"));
+/// let path = Path::new("unclassified_runs/fastq_pass/barcode01/reads_0.fastq");
+/// assert_eq!(barcode_of(path, &regex).as_deref(), Some("barcode01"));
+/// assert_eq!(barcode_of(Path::new("reads.fastq"), &regex), None);
+/// ```
+pub fn barcode_of(path: &Path, regex: &Regex) -> Option<String> {
+    let path = path.to_string_lossy();
+    regex.captures_iter(&path).last().and_then(|caps| {
+        caps.get(1)
+            .or_else(|| caps.get(0))
+            .map(|m| m.as_str().to_string())
+    })
+}
@@ -0,0 +1,181 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Marker file written into an output directory whose results are partial
+pub const INCOMPLETE_MARKER: &str = "INCOMPLETE";
+
+/// Checkpoint file written into the chunk directory when classify is interrupted
+pub const CHECKPOINT_FILE: &str = "checkpoint.json";
+
+/// Number of the termination signal received, 0 while running normally
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+#[cfg(unix)]
+extern "C" fn record_signal(signum: libc::c_int) {
+    SIGNAL.store(signum, Ordering::SeqCst);
+}
+
+/// Installs SIGINT and SIGTERM handlers that only record the signal.
+///
+/// Long running loops poll [`interrupted`] and stop at the next batch boundary, so
+/// outputs are flushed instead of being cut off mid-write. The handler is reset after
+/// the first signal, so a second Ctrl-C still terminates the process immediately.
+#[cfg(unix)]
+pub fn install_signal_handlers() {
+    for signum in [libc::SIGINT, libc::SIGTERM] {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = record_signal as extern "C" fn(libc::c_int) as usize;
+            action.sa_flags = libc::SA_RESETHAND | libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(signum, &action, std::ptr::null_mut()) != 0 {
+                eprintln!("Failed to install handler for signal {}", signum);
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+pub fn install_signal_handlers() {}
+
+/// Returns the received termination signal, if any
+pub fn interrupted() -> Option<i32> {
+    match SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        signum => Some(signum),
+    }
+}
+
+/// Error returned by a stage that stopped early because of a termination signal
+pub fn interrupted_error(stage: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Interrupted,
+        format!(
+            "{} interrupted by signal {}",
+            stage,
+            interrupted().unwrap_or_default()
+        ),
+    )
+}
+
+/// Reader wrapper that reports end of input once a termination signal was received
+///
+/// Works both for byte readers and for `seqkmer` sequence readers, so the parallel
+/// readers drain their queues and the writers flush as on a normal end of file.
+///
+/// `read_parallel` also ends the input at a read error, like a truncated file,
+/// without returning it, so the wrapper keeps the error for [`Interruptible::error`].
+pub struct Interruptible<R> {
+    inner: R,
+    error: Option<String>,
+}
+
+impl<R> Interruptible<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, error: None }
+    }
+
+    /// The read error that ended the input early, if any
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+}
+
+impl<R: Read> Read for Interruptible<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if interrupted().is_some() {
+            return Ok(0);
+        }
+        self.inner.read(buf)
+    }
+}
+
+impl<R: seqkmer::Reader> seqkmer::Reader for Interruptible<R> {
+    fn next(&mut self) -> Result<Option<Vec<seqkmer::Base<Vec<u8>>>>> {
+        if interrupted().is_some() {
+            return Ok(None);
+        }
+        self.inner
+            .next()
+            .inspect_err(|e| self.error = Some(e.to_string()))
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Writes the `INCOMPLETE` marker into `dir`, describing why its outputs are partial
+pub fn write_incomplete_marker<P: AsRef<Path>>(dir: P, stage: &str, detail: &str) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(dir.as_ref().join(INCOMPLETE_MARKER))?);
+    writeln!(writer, "stage\t{}", stage)?;
+    writeln!(writer, "signal\t{}", interrupted().unwrap_or_default())?;
+    writeln!(writer, "time\t{}", unix_time())?;
+    if !detail.is_empty() {
+        writeln!(writer, "{}", detail)?;
+    }
+    writer.flush()
+}
+
+/// Removes a stale `INCOMPLETE` marker after outputs were written completely
+pub fn clear_incomplete_marker<P: AsRef<Path>>(dir: P) {
+    let _ = fs::remove_file(dir.as_ref().join(INCOMPLETE_MARKER));
+}
+
+/// State of an interrupted classify run, written to `checkpoint.json` in the chunk directory
+///
+/// `classify --resume` continues the run from it: an interrupted splitr is redone,
+/// annotate continues with the chunk files left over and resolve is run again.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Stage that was running when the signal arrived
+    pub stage: String,
+    /// Page group the stage was running for, 0 for an ungrouped database
+    #[serde(default)]
+    pub group: usize,
+    /// Stages that finished before the signal
+    pub completed_stages: Vec<String>,
+    /// Received signal number
+    pub signal: i32,
+    /// Inputs the interrupted stage still has to process
+    pub pending_files: Vec<PathBuf>,
+    /// Seconds since the unix epoch
+    pub time: u64,
+}
+
+impl Checkpoint {
+    pub fn new(stage: &str, completed_stages: &[&str], pending_files: Vec<PathBuf>) -> Self {
+        Self {
+            stage: stage.to_string(),
+            group: 0,
+            completed_stages: completed_stages.iter().map(|s| s.to_string()).collect(),
+            signal: interrupted().unwrap_or_default(),
+            pending_files,
+            time: unix_time(),
+        }
+    }
+
+    pub fn write_to_dir<P: AsRef<Path>>(&self, dir: P) -> Result<PathBuf> {
+        let filename = dir.as_ref().join(CHECKPOINT_FILE);
+        let writer = BufWriter::new(File::create(&filename)?);
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(filename)
+    }
+
+    /// Reads the checkpoint left in `dir` by an interrupted run, if there is one
+    pub fn read_from_dir<P: AsRef<Path>>(dir: P) -> Result<Option<Self>> {
+        let filename = dir.as_ref().join(CHECKPOINT_FILE);
+        if !filename.exists() {
+            return Ok(None);
+        }
+        let reader = BufReader::new(File::open(filename)?);
+        Ok(Some(serde_json::from_reader(reader)?))
+    }
+}
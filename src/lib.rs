@@ -1,6 +1,8 @@
 mod kr2r_data;
 mod kv_store;
 pub mod readcounts;
+pub mod report_check;
+pub mod report_import;
 pub mod report;
 pub mod taxonomy;
 pub mod utils;
@@ -10,6 +12,16 @@ pub use kr2r_data::*;
 pub use kv_store::*;
 pub use readcounts::TaxonCounts;
 
+pub mod abundance;
 pub mod args;
+pub mod audit;
 pub mod classify;
 pub mod compact_hash;
+pub mod contamination;
+pub mod coverage;
+pub mod heatmap;
+pub mod inputs;
+pub mod interrupt;
+pub mod metadata;
+pub mod page_group;
+pub mod plugin;
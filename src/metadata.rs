@@ -0,0 +1,127 @@
+use crate::utils::open_file;
+use std::io::{self, BufRead, BufReader, Result};
+use std::path::Path;
+
+/// Sample sheet with arbitrary key/value metadata per sample
+///
+/// The sheet is a tab separated file with a header row. The first column identifies
+/// the sample, either by its file index in `sample_file.map`, by one of its input paths
+/// or by the file name of one of its input paths. The remaining columns are passed
+/// through to the outputs under their header names.
+#[derive(Debug, Default, Clone)]
+pub struct SampleMetadata {
+    columns: Vec<String>,
+    rows: Vec<(String, Vec<String>)>,
+}
+
+impl SampleMetadata {
+    /// Parses a sample sheet
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kun_peng::metadata::SampleMetadata;
+    ///
+    /// let sheet = "sample\tread_group\tsite\nreads_1.fq\tRG1\tgut\n2\tRG2\tskin\n";
+    /// let metadata = SampleMetadata::parse(sheet.as_bytes()).unwrap();
+    ///
+    /// let values = metadata.lookup(1, &["/data/reads_1.fq"]);
+    /// assert_eq!(values[0], ("read_group".to_string(), "RG1".to_string()));
+    /// assert_eq!(metadata.lookup(2, &["reads_2.fq"])[1].1, "skin");
+    /// assert!(metadata.lookup(3, &["reads_3.fq"]).is_empty());
+    /// ```
+    pub fn parse<R: BufRead>(reader: R) -> Result<Self> {
+        let mut lines = reader
+            .lines()
+            .filter(|line| !matches!(line, Ok(l) if l.trim().is_empty()));
+
+        let header = match lines.next() {
+            Some(line) => line?,
+            None => return Ok(Self::default()),
+        };
+        let columns: Vec<String> = header
+            .trim_end()
+            .split('\t')
+            .skip(1)
+            .map(|c| c.trim().to_string())
+            .collect();
+
+        let mut rows = Vec::new();
+        for line in lines {
+            let line = line?;
+            let mut fields = line.trim_end_matches(['\r', '\n']).split('\t');
+            let sample = fields.next().unwrap_or_default().trim().to_string();
+            let values: Vec<String> = fields.map(|v| v.trim().to_string()).collect();
+            if values.len() > columns.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "sample sheet row for {} has more fields than the header",
+                        sample
+                    ),
+                ));
+            }
+            rows.push((sample, values));
+        }
+
+        Ok(Self { columns, rows })
+    }
+
+    pub fn from_file<P: AsRef<Path>>(filename: P) -> Result<Self> {
+        Self::parse(BufReader::new(open_file(filename)?))
+    }
+
+    /// Returns the values of one column for all samples of the sheet
+    pub fn values<'a>(&'a self, column: &str) -> impl Iterator<Item = &'a str> + 'a {
+        let index = self.columns.iter().position(|c| c == column);
+        self.rows.iter().filter_map(move |(_, values)| {
+            index
+                .and_then(|i| values.get(i))
+                .map(|value| value.as_str())
+        })
+    }
+
+    /// Returns the metadata columns of the sample with the given file index and input paths
+    ///
+    /// Missing trailing fields are reported as empty values, an unknown sample yields no pairs.
+    pub fn lookup<S: AsRef<str>>(&self, file_index: usize, paths: &[S]) -> Vec<(String, String)> {
+        let index = file_index.to_string();
+        self.find(|sample| {
+            sample == index
+                || paths.iter().any(|path| {
+                    let path = Path::new(path.as_ref());
+                    path == Path::new(sample) || path.file_name().is_some_and(|name| name == sample)
+                })
+        })
+    }
+
+    /// Returns the metadata columns of the sample named exactly `name`, e.g. a barcode
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kun_peng::metadata::SampleMetadata;
+    ///
+    /// let sheet = "sample\tsite\nbarcode01\tgut\n";
+    /// let metadata = SampleMetadata::parse(sheet.as_bytes()).unwrap();
+    /// assert_eq!(metadata.lookup_name("barcode01")[0].1, "gut");
+    /// assert!(metadata.lookup_name("barcode02").is_empty());
+    /// ```
+    pub fn lookup_name(&self, name: &str) -> Vec<(String, String)> {
+        self.find(|sample| sample == name)
+    }
+
+    fn find<F: Fn(&str) -> bool>(&self, matches: F) -> Vec<(String, String)> {
+        self.rows
+            .iter()
+            .find(|(sample, _)| matches(sample))
+            .map(|(_, values)| {
+                self.columns
+                    .iter()
+                    .enumerate()
+                    .map(|(i, column)| (column.clone(), values.get(i).cloned().unwrap_or_default()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,326 @@
+use crate::audit::warn;
+use crate::compact_hash::Row;
+use crate::taxonomy::Taxonomy;
+use crate::utils::{find_files, open_file};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::{self, create_dir_all, File};
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+
+/// Page group index of a database built with `--page-group-rank`
+///
+/// One `taxid<TAB>name<TAB>sequences<TAB>bases` line per group, the taxid being
+/// the clade the group was built from.
+pub const PAGE_GROUPS_FILENAME: &str = "page_groups.tsv";
+
+/// Directory of the page groups, each group is a complete database in `groups/<taxid>`
+pub const PAGE_GROUPS_DIR: &str = "groups";
+
+/// Files every page group shares with the database it was split from
+const SHARED_FILES: [&str; 2] = ["taxo.k2d", "seqid2taxid.map"];
+
+/// The part of a database built from the library sequences of one clade
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageGroup {
+    /// External taxid of the clade, 1 for the sequences without an ancestor at the group rank
+    pub taxid: u64,
+    pub name: String,
+    pub sequences: u64,
+    pub bases: u64,
+}
+
+impl PageGroup {
+    /// Database directory of the group
+    pub fn dir<P: AsRef<Path>>(&self, database: P) -> PathBuf {
+        database
+            .as_ref()
+            .join(PAGE_GROUPS_DIR)
+            .join(self.taxid.to_string())
+    }
+}
+
+/// Whether the database was split into page groups
+pub fn is_grouped<P: AsRef<Path>>(database: P) -> bool {
+    database.as_ref().join(PAGE_GROUPS_FILENAME).exists()
+}
+
+/// Reads the page group index of a database
+pub fn read_page_groups<P: AsRef<Path>>(database: P) -> Result<Vec<PageGroup>> {
+    let reader = BufReader::new(open_file(database.as_ref().join(PAGE_GROUPS_FILENAME))?);
+    let mut groups = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let fields: Vec<&str> = line.trim_end().split('\t').collect();
+        if fields.len() < 4 {
+            continue;
+        }
+        if let (Ok(taxid), Ok(sequences), Ok(bases)) = (
+            fields[0].parse::<u64>(),
+            fields[2].parse::<u64>(),
+            fields[3].parse::<u64>(),
+        ) {
+            groups.push(PageGroup {
+                taxid,
+                name: fields[1].to_string(),
+                sequences,
+                bases,
+            });
+        }
+    }
+    Ok(groups)
+}
+
+/// Writes the page group index of a database
+pub fn write_page_groups<P: AsRef<Path>>(database: P, groups: &[PageGroup]) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(database.as_ref().join(PAGE_GROUPS_FILENAME))?);
+    writeln!(writer, "taxid\tname\tsequences\tbases")?;
+    for group in groups {
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}",
+            group.taxid, group.name, group.sequences, group.bases
+        )?;
+    }
+    writer.flush()
+}
+
+/// Selects the page groups of the given clades, all groups when `taxids` is empty
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::page_group::{select_page_groups, PageGroup};
+///
+/// let group = |taxid: u64, name: &str| PageGroup {
+///     taxid,
+///     name: name.to_string(),
+///     sequences: 1,
+///     bases: 100,
+/// };
+/// let groups = vec![group(2, "Bacteria"), group(2157, "Archaea"), group(10239, "Viruses")];
+///
+/// assert_eq!(select_page_groups(&groups, &[]).unwrap().len(), 3);
+/// let selected = select_page_groups(&groups, &[10239, 2]).unwrap();
+/// assert_eq!(selected.iter().map(|g| g.taxid).collect::<Vec<_>>(), vec![2, 10239]);
+/// assert!(select_page_groups(&groups, &[2759]).is_err());
+/// ```
+pub fn select_page_groups(groups: &[PageGroup], taxids: &[u64]) -> Result<Vec<PageGroup>> {
+    if let Some(missing) = taxids
+        .iter()
+        .find(|taxid| !groups.iter().any(|group| group.taxid == **taxid))
+    {
+        let available: Vec<String> = groups
+            .iter()
+            .map(|group| format!("{} ({})", group.taxid, group.name))
+            .collect();
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "no page group for taxid {}, available: {}",
+                missing,
+                available.join(", ")
+            ),
+        ));
+    }
+    Ok(groups
+        .iter()
+        .filter(|group| taxids.is_empty() || taxids.contains(&group.taxid))
+        .cloned()
+        .collect())
+}
+
+/// Database directories to classify against
+///
+/// The selected page groups (all groups when `taxids` is empty) of a database
+/// built with `--page-group-rank`, the database itself otherwise.
+pub fn database_dirs<P: AsRef<Path>>(database: P, taxids: &[u64]) -> Result<Vec<PathBuf>> {
+    let database = database.as_ref();
+    if !is_grouped(database) {
+        if !taxids.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{} was not built with --page-group-rank",
+                    database.display()
+                ),
+            ));
+        }
+        return Ok(vec![database.to_path_buf()]);
+    }
+    let groups = read_page_groups(database)?;
+    Ok(select_page_groups(&groups, taxids)?
+        .iter()
+        .map(|group| group.dir(database))
+        .collect())
+}
+
+/// Merges the hits of one read that several page groups found for the same minimizer
+///
+/// `rows` must be sorted by `kmer_id`. The hits of a minimizer are merged into the
+/// first one, its taxon (the bits of `value_mask`) being the `lca` of all their taxa,
+/// so each minimizer votes once, for the taxon an ungrouped database would hold.
+/// This also drops the rows written again by an annotate resumed with `classify --resume`
+/// for the chunk file it was interrupted in.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::compact_hash::Row;
+/// use kun_peng::page_group::merge_group_rows;
+///
+/// // taxon in the low 8 bits
+/// let mut rows = vec![
+///     Row::new(0x200 | 7, 1, 1),
+///     Row::new(0x100 | 5, 1, 3),
+///     Row::new(0x300 | 6, 1, 3),
+/// ];
+/// merge_group_rows(&mut rows, 0xff, |a, b| if a == b { a } else { 2 });
+/// assert_eq!(rows.len(), 2);
+/// assert_eq!(rows[1].kmer_id, 3);
+/// assert_eq!(rows[1].value, 0x100 | 2);
+/// ```
+pub fn merge_group_rows<F>(rows: &mut Vec<Row>, value_mask: usize, lca: F)
+where
+    F: Fn(u32, u32) -> u32,
+{
+    let mask = value_mask as u32;
+    rows.dedup_by(|row, kept| {
+        if row.kmer_id != kept.kmer_id {
+            return false;
+        }
+        let taxid = lca(kept.value & mask, row.value & mask);
+        kept.value = (kept.value & !mask) | taxid;
+        true
+    });
+}
+
+/// Hash table header used to resolve the hits of a database
+///
+/// The page groups of a database share its taxonomy, so their cells use the same
+/// value bits and the header of any group will do.
+pub fn hash_config_file<P: AsRef<Path>>(database: P) -> Result<PathBuf> {
+    let database = database.as_ref();
+    if !is_grouped(database) {
+        return Ok(database.join("hash_config.k2d"));
+    }
+    match read_page_groups(database)?.first() {
+        Some(group) => Ok(group.dir(database).join("hash_config.k2d")),
+        None => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "{:?} lists no page groups",
+                database.join(PAGE_GROUPS_FILENAME)
+            ),
+        )),
+    }
+}
+
+/// External taxid of the ancestor of a taxon at `rank`, 1 (root) if it has none
+fn clade_at_rank(taxonomy: &Taxonomy, external_id: u64, rank: &str) -> u64 {
+    let mut taxid = taxonomy.get_internal_id(external_id);
+    while taxid != 0 {
+        if taxonomy.rank_of(taxid) == rank {
+            return taxonomy.nodes[taxid as usize].external_id;
+        }
+        taxid = taxonomy.nodes[taxid as usize].parent_id as u32;
+    }
+    1
+}
+
+/// Creates the directory of a page group and opens the library file of the group
+fn create_group_library(database: &Path, group: &PageGroup) -> Result<BufWriter<File>> {
+    let group_dir = group.dir(database);
+    let library_dir = group_dir.join("library");
+    create_dir_all(&library_dir)?;
+    for name in SHARED_FILES {
+        let target = group_dir.join(name);
+        if !target.exists() {
+            // 硬链接失败(例如跨文件系统)时复制
+            fs::hard_link(database.join(name), &target)
+                .or_else(|_| fs::copy(database.join(name), &target).map(|_| ()))?;
+        }
+    }
+    let file = File::create(library_dir.join("library.fna"))?;
+    Ok(BufWriter::new(file))
+}
+
+/// Splits the library of a database by the clades at `rank`
+///
+/// The sequences of each clade are written to the library of its page group,
+/// which also gets the taxonomy and sequence id map of the database, ready to be
+/// built like a database of its own. Sequences missing from the id map are left
+/// out, as the build does.
+pub fn split_library<P: AsRef<Path>>(
+    database: P,
+    taxonomy: &Taxonomy,
+    id_to_taxon_map: &HashMap<String, u64>,
+    rank: &str,
+) -> Result<Vec<PageGroup>> {
+    let database = database.as_ref();
+    let mut groups: HashMap<u64, PageGroup> = HashMap::new();
+    let mut writers: HashMap<u64, BufWriter<File>> = HashMap::new();
+    let mut clades: HashMap<u64, u64> = HashMap::new();
+
+    for fna_file in find_files(database.join("library"), "library", ".fna") {
+        let reader = BufReader::new(open_file(&fna_file)?);
+        let mut current: Option<u64> = None;
+        for line in reader.lines() {
+            let line = line?;
+            if let Some(header) = line.strip_prefix('>') {
+                let seq_id = header.split_whitespace().next().unwrap_or("");
+                current = id_to_taxon_map.get(seq_id).map(|&taxid| {
+                    *clades
+                        .entry(taxid)
+                        .or_insert_with(|| clade_at_rank(taxonomy, taxid, rank))
+                });
+                if let Some(clade) = current {
+                    groups
+                        .entry(clade)
+                        .or_insert_with(|| PageGroup {
+                            taxid: clade,
+                            name: taxonomy
+                                .name_of(taxonomy.get_internal_id(clade))
+                                .to_string(),
+                            sequences: 0,
+                            bases: 0,
+                        })
+                        .sequences += 1;
+                } else {
+                    warn(
+                        "build",
+                        "missing_seqid_taxid",
+                        None,
+                        &format!(
+                            "sequence {} has no taxid in seqid2taxid.map, skipped",
+                            seq_id
+                        ),
+                    );
+                }
+            } else if let Some(clade) = current {
+                if let Some(group) = groups.get_mut(&clade) {
+                    group.bases += line.trim_end().len() as u64;
+                }
+            }
+
+            let clade = match current {
+                Some(clade) => clade,
+                None => continue,
+            };
+            let writer = match writers.entry(clade) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    entry.insert(create_group_library(database, &groups[&clade])?)
+                }
+            };
+            writeln!(writer, "{}", line)?;
+        }
+    }
+    for writer in writers.values_mut() {
+        writer.flush()?;
+    }
+
+    let mut groups: Vec<PageGroup> = groups.into_values().collect();
+    groups.sort_by_key(|group| group.taxid);
+    Ok(groups)
+}
@@ -0,0 +1,332 @@
+use crate::audit::tally;
+use crate::readcounts::TaxonCounters;
+use crate::taxonomy::Taxonomy;
+use std::io::Result;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Final classification of one read, as written to the per-read output
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadCall {
+    /// `C` when `true`, `U` otherwise
+    pub classified: bool,
+    pub read_id: String,
+    /// External taxid of the call
+    pub taxid: u64,
+    /// Sequence length column, `len` or `len1|len2` for pairs
+    pub size: String,
+    /// Minimizer hit list column
+    pub hit_list: String,
+    /// Extra columns appended after the hit list
+    pub annotations: Vec<String>,
+}
+
+impl ReadCall {
+    /// Formats the call as a line of the per-read output
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kun_peng::plugin::ReadCall;
+    ///
+    /// let mut call = ReadCall {
+    ///     classified: true,
+    ///     read_id: "read_1".to_string(),
+    ///     taxid: 9606,
+    ///     size: "150".to_string(),
+    ///     hit_list: "9606:12".to_string(),
+    ///     annotations: Vec::new(),
+    /// };
+    /// assert_eq!(call.to_line(), "C\tread_1\t9606\t150\t9606:12\n");
+    ///
+    /// call.annotations.push("blocklisted".to_string());
+    /// assert_eq!(call.to_line(), "C\tread_1\t9606\t150\t9606:12\tblocklisted\n");
+    /// ```
+    pub fn to_line(&self) -> String {
+        let mut line = format!(
+            "{}\t{}\t{}\t{}\t{}",
+            if self.classified { "C" } else { "U" },
+            self.read_id,
+            self.taxid,
+            self.size,
+            self.hit_list
+        );
+        for annotation in &self.annotations {
+            line.push('\t');
+            line.push_str(annotation);
+        }
+        line.push('\n');
+        line
+    }
+}
+
+/// Hook receiving each finalized per-read classification before it is written
+///
+/// Implementations may change the call, attach annotations or drop the read from
+/// the per-read output by returning `false`. Reports count the reads by their
+/// final call, see [`PostProcessors::apply_counted`].
+pub trait ReadPostProcessor: Send + Sync {
+    fn process(&self, call: &mut ReadCall) -> bool;
+}
+
+/// Ordered list of post-processors applied to every read
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::plugin::{PostProcessors, ReadCall, ReadPostProcessor};
+///
+/// // lab-specific blocklist: calls of these taxa are reported as unclassified
+/// struct Blocklist(Vec<u64>);
+///
+/// impl ReadPostProcessor for Blocklist {
+///     fn process(&self, call: &mut ReadCall) -> bool {
+///         if self.0.contains(&call.taxid) {
+///             call.classified = false;
+///             call.taxid = 0;
+///             call.annotations.push("blocklisted".to_string());
+///         }
+///         true
+///     }
+/// }
+///
+/// let mut post_processors = PostProcessors::default();
+/// post_processors.push(Blocklist(vec![32630]));
+///
+/// let mut call = ReadCall {
+///     classified: true,
+///     read_id: "read_1".to_string(),
+///     taxid: 32630,
+///     size: "150".to_string(),
+///     hit_list: "32630:12".to_string(),
+///     annotations: Vec::new(),
+/// };
+/// assert!(post_processors.apply(&mut call));
+/// assert_eq!(call.to_line(), "U\tread_1\t0\t150\t32630:12\tblocklisted\n");
+/// ```
+#[derive(Default)]
+pub struct PostProcessors {
+    processors: Vec<Box<dyn ReadPostProcessor>>,
+    /// Reads called as another taxon by a post-processor, see [`PostProcessors::tally_remapped`]
+    remapped: AtomicU64,
+    /// Reads called by a post-processor as a taxid missing from the taxonomy
+    unknown_taxids: AtomicU64,
+}
+
+impl PostProcessors {
+    pub fn push<P: ReadPostProcessor + 'static>(&mut self, processor: P) {
+        self.processors.push(Box::new(processor));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.processors.is_empty()
+    }
+
+    /// Runs all post-processors in order, returns `false` if one of them dropped the read
+    pub fn apply(&self, call: &mut ReadCall) -> bool {
+        self.processors
+            .iter()
+            .all(|processor| processor.process(call))
+    }
+
+    /// Runs all post-processors and moves the reads of the call in `taxon_counts` to the final call
+    ///
+    /// `taxon_counts` are the counters of the read built by the classifier, keyed by
+    /// internal id, and `reads` is the number of reads the call stands for. A read
+    /// that is dropped, set to unclassified or called as a taxid missing from
+    /// `taxonomy` counts as unclassified. The k-mer counts stay with the taxa hit.
+    ///
+    /// Returns whether the read is kept in the per-read output and whether it counts
+    /// as classified.
+    pub fn apply_counted(
+        &self,
+        call: &mut ReadCall,
+        reads: u64,
+        taxonomy: &Taxonomy,
+        taxon_counts: &mut TaxonCounters,
+    ) -> (bool, bool) {
+        let internal_id = |call: &ReadCall| match call.classified {
+            true => taxonomy.get_internal_id(call.taxid) as u64,
+            false => 0,
+        };
+        let called = internal_id(call);
+        let kept = self.apply(call);
+        let taxid = if kept { internal_id(call) } else { 0 };
+        if taxid != called {
+            if let Some(counter) = taxon_counts.get_mut(&called) {
+                counter.set_read_count(counter.read_count().saturating_sub(reads));
+            }
+            if taxid > 0 {
+                let counter = taxon_counts.entry(taxid).or_default();
+                counter.set_read_count(counter.read_count() + reads);
+            }
+            if kept && call.classified {
+                let remapped = match taxid {
+                    0 => &self.unknown_taxids,
+                    _ => &self.remapped,
+                };
+                remapped.fetch_add(reads, Ordering::Relaxed);
+            }
+        }
+        (kept, taxid > 0)
+    }
+
+    /// Records the reads of a sample the post-processors called as another taxon, and
+    /// those they called as a taxid missing from the taxonomy, in the audit findings
+    ///
+    /// Both counts restart for the next sample.
+    pub fn tally_remapped(&self, stage: &str, sample: usize) {
+        let remapped = self.remapped.swap(0, Ordering::Relaxed);
+        if remapped > 0 {
+            tally(
+                stage,
+                "remapped_taxids",
+                Some(sample),
+                remapped,
+                &format!(
+                    "post-processors called {} reads of sample {} as another taxon",
+                    remapped, sample
+                ),
+            );
+        }
+        let unknown = self.unknown_taxids.swap(0, Ordering::Relaxed);
+        if unknown > 0 {
+            tally(
+                stage,
+                "remapped_unknown_taxids",
+                Some(sample),
+                unknown,
+                &format!(
+                    "post-processors called {} reads of sample {} as taxids missing from the taxonomy, counted as unclassified",
+                    unknown, sample
+                ),
+            );
+        }
+    }
+
+    /// Loads a post-processor from each dynamic library, see `DylibPostProcessor`
+    ///
+    /// Requires the `dylib_plugins` feature, an error is returned otherwise.
+    pub fn from_libraries<P: AsRef<Path>>(libraries: &[P]) -> Result<Self> {
+        #[cfg(all(feature = "dylib_plugins", unix))]
+        {
+            let mut post_processors = Self::default();
+            for library in libraries {
+                post_processors.push(DylibPostProcessor::open(library)?);
+            }
+            Ok(post_processors)
+        }
+
+        #[cfg(not(all(feature = "dylib_plugins", unix)))]
+        match libraries.first() {
+            Some(library) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!(
+                    "cannot load plugin {:?}: kun_peng was built without the `dylib_plugins` feature",
+                    library.as_ref()
+                ),
+            )),
+            None => Ok(Self::default()),
+        }
+    }
+}
+
+#[cfg(all(feature = "dylib_plugins", unix))]
+pub use dylib::{DylibPostProcessor, HOOK_SYMBOL};
+
+#[cfg(all(feature = "dylib_plugins", unix))]
+mod dylib {
+    use super::{ReadCall, ReadPostProcessor};
+    use std::ffi::{CStr, CString};
+    use std::io::{self, Result};
+    use std::os::raw::{c_char, c_int, c_void};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+
+    /// Name of the hook function a plugin library has to export
+    pub const HOOK_SYMBOL: &str = "kun_peng_read_hook";
+
+    type HookFn = unsafe extern "C" fn(*const c_char, *mut u8, *mut u64) -> c_int;
+
+    /// Post-processor implemented by a dynamic library with a C ABI
+    ///
+    /// The library exports
+    ///
+    /// ```c
+    /// int kun_peng_read_hook(const char *read_id, uint8_t *classified, uint64_t *taxid);
+    /// ```
+    ///
+    /// which may update `classified` and `taxid` in place and returns non-zero to drop
+    /// the read from the per-read output. The hook is called from several threads at
+    /// once and must be thread-safe.
+    pub struct DylibPostProcessor {
+        path: PathBuf,
+        handle: *mut c_void,
+        hook: HookFn,
+    }
+
+    // the hook is required to be thread-safe, the handle is only used to unload the library
+    unsafe impl Send for DylibPostProcessor {}
+    unsafe impl Sync for DylibPostProcessor {}
+
+    fn dl_error(path: &Path) -> io::Error {
+        let message = unsafe {
+            let error = libc::dlerror();
+            if error.is_null() {
+                "unknown error".to_string()
+            } else {
+                CStr::from_ptr(error).to_string_lossy().into_owned()
+            }
+        };
+        io::Error::other(format!("failed to load plugin {:?}: {}", path, message))
+    }
+
+    impl DylibPostProcessor {
+        pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+            let path = path.as_ref().to_path_buf();
+            let filename = CString::new(path.as_os_str().as_bytes())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let symbol = CString::new(HOOK_SYMBOL).unwrap();
+
+            unsafe {
+                let handle = libc::dlopen(filename.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL);
+                if handle.is_null() {
+                    return Err(dl_error(&path));
+                }
+                let hook = libc::dlsym(handle, symbol.as_ptr());
+                if hook.is_null() {
+                    let err = dl_error(&path);
+                    libc::dlclose(handle);
+                    return Err(err);
+                }
+                let hook = std::mem::transmute::<*mut c_void, HookFn>(hook);
+                Ok(Self { path, handle, hook })
+            }
+        }
+
+        pub fn path(&self) -> &Path {
+            &self.path
+        }
+    }
+
+    impl ReadPostProcessor for DylibPostProcessor {
+        fn process(&self, call: &mut ReadCall) -> bool {
+            // read ids never contain NUL bytes, fall back to an empty id just in case
+            let read_id = CString::new(call.read_id.as_str()).unwrap_or_default();
+            let mut classified = call.classified as u8;
+            let mut taxid = call.taxid;
+            let drop = unsafe { (self.hook)(read_id.as_ptr(), &mut classified, &mut taxid) };
+            call.classified = classified != 0;
+            call.taxid = taxid;
+            drop == 0
+        }
+    }
+
+    impl Drop for DylibPostProcessor {
+        fn drop(&mut self) {
+            unsafe {
+                libc::dlclose(self.handle);
+            }
+        }
+    }
+}
@@ -93,6 +93,11 @@ where
         self.n_reads.fetch_add(1, Ordering::SeqCst);
     }
 
+    /// Sets the read count only, the k-mers stay with the taxon they hit
+    pub fn set_read_count(&mut self, n_reads: u64) {
+        self.n_reads.store(n_reads, Ordering::SeqCst);
+    }
+
     pub fn kmer_count(&self) -> u64 {
         self.n_kmers.load(Ordering::SeqCst)
     }
@@ -106,6 +111,27 @@ where
         self.kmers.add_kmer(kmer);
     }
 
+    /// Counts the reads and k-mers `factor` times, e.g. for a read standing for
+    /// `factor` collapsed duplicates. Distinct k-mers are unchanged.
+    pub fn multiply(&mut self, factor: u64) {
+        let n_reads = self.read_count();
+        let n_kmers = self.kmer_count();
+        self.n_reads.store(n_reads * factor, Ordering::SeqCst);
+        self.n_kmers.store(n_kmers * factor, Ordering::SeqCst);
+    }
+
+    /// Removes `n` reads, e.g. attributed to contamination. The k-mer count shrinks
+    /// proportionally, distinct k-mers are unchanged.
+    pub fn remove_reads(&mut self, n: u64) {
+        let n_reads = self.read_count();
+        let remaining = n_reads.saturating_sub(n);
+        if n_reads > 0 {
+            let n_kmers = self.kmer_count() as u128 * remaining as u128 / n_reads as u128;
+            self.n_kmers.store(n_kmers as u64, Ordering::SeqCst);
+        }
+        self.n_reads.store(remaining, Ordering::SeqCst);
+    }
+
     pub fn merge(&mut self, other: &ReadCounts<T>) -> Result<(), UnionError> {
         self.n_reads.fetch_add(other.read_count(), Ordering::SeqCst);
         self.n_kmers.fetch_add(other.kmer_count(), Ordering::SeqCst);
@@ -1,6 +1,8 @@
+use crate::coverage::minimizer_fraction;
 use crate::readcounts::{ReadCounter, TaxonCounters};
 use crate::taxonomy::Taxonomy;
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use std::fs::File;
 use std::io::{self, Write};
@@ -67,7 +69,7 @@ pub fn get_clade_counters(taxonomy: &Taxonomy, call_counters: &TaxonCounters) ->
 /// # Returns
 ///
 /// A string slice extracted from the byte slice
-fn extract_string_from_offset(data: &[u8], offset: usize) -> &str {
+pub(crate) fn extract_string_from_offset(data: &[u8], offset: usize) -> &str {
     let end = data[offset..]
         .iter()
         .position(|&c| c == b'\0')
@@ -224,7 +226,7 @@ pub fn report_mpa_style<P: AsRef<Path>>(
 /// # Arguments
 ///
 /// * `file` - The file to write to
-/// * `report_kmer_data` - Whether to report k-mer data
+/// * `options` - Report layout options
 /// * `total_seqs` - The total number of sequences
 /// * `clade_counter` - The ReadCounter for the clade
 /// * `taxon_counter` - The ReadCounter for the taxon
@@ -238,7 +240,7 @@ pub fn report_mpa_style<P: AsRef<Path>>(
 /// An io::Result indicating success or failure of the write operation
 pub fn print_kraken_style_report_line(
     file: &mut File,
-    report_kmer_data: bool,
+    options: &ReportOptions,
     total_seqs: u64,
     clade_counter: &mut ReadCounter,
     taxon_counter: &ReadCounter,
@@ -247,8 +249,7 @@ pub fn print_kraken_style_report_line(
     sci_name: &str,
     depth: usize,
 ) -> io::Result<()> {
-    let pct = 100.0 * clade_counter.read_count() as f64 / total_seqs as f64;
-    let pct_str = format!("{:6.2}", pct);
+    let pct_str = options.format_percentage(clade_counter.read_count(), total_seqs);
 
     write!(
         file,
@@ -258,7 +259,7 @@ pub fn print_kraken_style_report_line(
         taxon_counter.read_count()
     )?;
 
-    if report_kmer_data {
+    if options.report_kmer_data {
         write!(
             file,
             "\t{}\t{}",
@@ -267,10 +268,18 @@ pub fn print_kraken_style_report_line(
         )?;
     }
 
+    if let Some(clade_minimizers) = &options.clade_minimizers {
+        let observed = clade_counter.distinct_kmer_count() as u64;
+        let reference = *clade_minimizers.get(&(taxid as u64)).unwrap_or(&0);
+        write!(file, "\t{:.4}", minimizer_fraction(observed, reference))?;
+    }
+
     write!(file, "\t{}\t{}\t", rank_str, taxid)?;
 
-    for _ in 0..depth {
-        write!(file, "  ")?;
+    if !options.tab_only {
+        for _ in 0..depth {
+            write!(file, "  ")?;
+        }
     }
 
     writeln!(file, "{}", sci_name)
@@ -282,8 +291,7 @@ pub fn print_kraken_style_report_line(
 ///
 /// * `taxid` - The current taxon ID
 /// * `file` - The file to write the report to
-/// * `report_zeros` - Whether to report zero counts
-/// * `report_kmer_data` - Whether to report k-mer data
+/// * `options` - Report layout options
 /// * `taxonomy` - The taxonomy structure
 /// * `clade_counters` - A mutable reference to TaxonCounters for clade counts
 /// * `call_counters` - A reference to TaxonCounters for call counts
@@ -298,8 +306,7 @@ pub fn print_kraken_style_report_line(
 pub fn kraken_report_dfs(
     taxid: u64,
     file: &mut File,
-    report_zeros: bool,
-    report_kmer_data: bool,
+    options: &ReportOptions,
     taxonomy: &Taxonomy,
     clade_counters: &mut HashMap<u64, ReadCounter>,
     call_counters: &HashMap<u64, ReadCounter>,
@@ -308,7 +315,7 @@ pub fn kraken_report_dfs(
     rank_depth: i32,
     depth: usize,
 ) -> io::Result<()> {
-    if !report_zeros && clade_counters.get(&taxid).map_or(0, |c| c.read_count()) == 0 {
+    if !options.report_zeros && clade_counters.get(&taxid).map_or(0, |c| c.read_count()) == 0 {
         return Ok(());
     }
 
@@ -349,7 +356,7 @@ pub fn kraken_report_dfs(
 
     print_kraken_style_report_line(
         file,
-        report_kmer_data,
+        options,
         total_seqs,
         &mut clade_counter,
         call_counters.get(&taxid).unwrap_or(&ReadCounter::default()),
@@ -359,6 +366,23 @@ pub fn kraken_report_dfs(
         depth,
     )?;
 
+    if taxid == 1 {
+        if let Some(unclassified) = clade_counters.get_mut(&UNCLASSIFIED_NODE) {
+            let taxon_counter = ReadCounter::new(unclassified.read_count(), 0);
+            print_kraken_style_report_line(
+                file,
+                options,
+                total_seqs,
+                unclassified,
+                &taxon_counter,
+                "U",
+                options.unclassified_taxid,
+                "unclassified",
+                depth + 1,
+            )?;
+        }
+    }
+
     let mut children: Vec<u64> = (0..node.child_count)
         .map(|i| node.first_child + i)
         .collect();
@@ -374,8 +398,7 @@ pub fn kraken_report_dfs(
         kraken_report_dfs(
             child_taxid,
             file,
-            report_zeros,
-            report_kmer_data,
+            options,
             taxonomy,
             clade_counters,
             call_counters,
@@ -389,13 +412,109 @@ pub fn kraken_report_dfs(
     Ok(())
 }
 
+/// Key of the synthetic unclassified node in the clade counters.
+/// Internal taxid 0 is not a taxonomy node, so it never collides with a real clade.
+const UNCLASSIFIED_NODE: u64 = 0;
+
+/// Options controlling the content of a Kraken-style report
+#[derive(Debug, Clone)]
+pub struct ReportOptions {
+    /// Whether to report zero counts
+    pub report_zeros: bool,
+    /// Whether to report k-mer data
+    pub report_kmer_data: bool,
+    /// Sample metadata written as `# key: value` lines before the report rows, empty values are skipped
+    pub metadata: Vec<(String, String)>,
+    /// Number of decimals of the percentage column
+    pub precision: usize,
+    /// Separate fields by tabs only, without padding the percentage or indenting names
+    pub tab_only: bool,
+    /// Taxid reported for unclassified reads
+    pub unclassified_taxid: u32,
+    /// Report unclassified reads as a synthetic child of root instead of a line before root
+    pub unclassified_under_root: bool,
+    /// Reference minimizers per clade (external id), adds a minimizer fraction column
+    pub clade_minimizers: Option<Arc<HashMap<u64, u64>>>,
+}
+
+impl Default for ReportOptions {
+    fn default() -> Self {
+        Self::new(false, false)
+    }
+}
+
+impl ReportOptions {
+    pub fn new(report_zeros: bool, report_kmer_data: bool) -> Self {
+        Self {
+            report_zeros,
+            report_kmer_data,
+            metadata: Vec::new(),
+            precision: 2,
+            tab_only: false,
+            unclassified_taxid: 0,
+            unclassified_under_root: false,
+            clade_minimizers: None,
+        }
+    }
+
+    /// Formats the percentage column
+    ///
+    /// Rust formatting does not depend on the locale, so the decimal separator is
+    /// always `.`. Unless `tab_only` is set the value is right aligned to the width
+    /// of `100` with `precision` decimals, like Kraken 2 does for two decimals.
+    /// An empty sample reports `0` instead of `NaN`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kun_peng::report::ReportOptions;
+    ///
+    /// let mut options = ReportOptions::default();
+    /// assert_eq!(options.format_percentage(1, 3), " 33.33");
+    /// assert_eq!(options.format_percentage(0, 0), "  0.00");
+    ///
+    /// options.precision = 4;
+    /// options.tab_only = true;
+    /// assert_eq!(options.format_percentage(1, 3), "33.3333");
+    /// ```
+    pub fn format_percentage(&self, count: u64, total: u64) -> String {
+        let pct = if total == 0 {
+            0.0
+        } else {
+            100.0 * count as f64 / total as f64
+        };
+        if self.tab_only {
+            format!("{:.*}", self.precision, pct)
+        } else {
+            let width = if self.precision == 0 {
+                3
+            } else {
+                self.precision + 4
+            };
+            format!(
+                "{:>width$.prec$}",
+                pct,
+                width = width,
+                prec = self.precision
+            )
+        }
+    }
+
+    /// Returns a copy of the options carrying the metadata of one sample
+    pub fn with_metadata(&self, metadata: Vec<(String, String)>) -> Self {
+        Self {
+            metadata,
+            ..self.clone()
+        }
+    }
+}
+
 /// Generates a Kraken-style report
 ///
 /// # Arguments
 ///
 /// * `filename` - The path to the output file
-/// * `report_zeros` - Whether to report zero counts
-/// * `report_kmer_data` - Whether to report k-mer data
+/// * `options` - Report layout options and sample metadata
 /// * `taxonomy` - The taxonomy structure
 /// * `call_counters` - A HashMap of taxon IDs to their ReadCounters
 /// * `total_seqs` - The total number of sequences
@@ -406,8 +525,7 @@ pub fn kraken_report_dfs(
 /// An io::Result indicating success or failure of the operation
 pub fn report_kraken_style<P: AsRef<Path>>(
     filename: P,
-    report_zeros: bool,
-    report_kmer_data: bool,
+    options: &ReportOptions,
     taxonomy: &Taxonomy,
     call_counters: &HashMap<u64, ReadCounter>,
     total_seqs: u64,
@@ -417,18 +535,34 @@ pub fn report_kraken_style<P: AsRef<Path>>(
 
     let mut file = File::create(filename)?;
 
+    for (key, value) in options
+        .metadata
+        .iter()
+        .filter(|(_, value)| !value.is_empty())
+    {
+        writeln!(file, "# {}: {}", key, value)?;
+    }
+
     // Handle the special case for unclassified sequences
-    if total_unclassified != 0 || report_zeros {
+    if options.unclassified_under_root {
+        if total_unclassified != 0 || options.report_zeros {
+            let _ = clade_counters
+                .entry(1)
+                .or_default()
+                .merge(&ReadCounter::new(total_unclassified, 0));
+            clade_counters.insert(UNCLASSIFIED_NODE, ReadCounter::new(total_unclassified, 0));
+        }
+    } else if total_unclassified != 0 || options.report_zeros {
         let mut rc = ReadCounter::new(total_unclassified, 0);
         let trc = ReadCounter::new(total_unclassified, 0);
         print_kraken_style_report_line(
             &mut file,
-            report_kmer_data,
+            options,
             total_seqs,
             &mut rc,
             &trc,
             "U",
-            0,
+            options.unclassified_taxid,
             "unclassified",
             0,
         )?;
@@ -438,8 +572,7 @@ pub fn report_kraken_style<P: AsRef<Path>>(
     kraken_report_dfs(
         1,
         &mut file,
-        report_zeros,
-        report_kmer_data,
+        options,
         taxonomy,
         &mut clade_counters,
         call_counters,
@@ -0,0 +1,230 @@
+use crate::report_import::ReportEntry;
+use crate::utils::open_file;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Result};
+use std::path::Path;
+
+/// Hits per bin needed before the position checks of a taxon are trusted
+const MIN_HITS_PER_BIN: u64 = 5;
+
+/// Thresholds of the report sanity checks
+#[derive(Debug, Clone)]
+pub struct CheckThresholds {
+    /// Only taxa with at least this abundance (percent of all reads) are checked
+    pub min_abundance: f64,
+    /// Fewer distinct minimizers than this point to hits on a tiny part of the genome
+    pub min_distinct_minimizers: u64,
+    /// A minimizer fraction below this means next to none of the reference minimizers was seen
+    pub min_minimizer_fraction: f64,
+    /// Normalized entropy of the hit positions below which hits count as clustered
+    pub min_evenness: f64,
+    /// How many times their even share the first and last position bin may hold before
+    /// the hits count as read-end hits
+    pub max_end_enrichment: f64,
+}
+
+impl Default for CheckThresholds {
+    fn default() -> Self {
+        Self {
+            min_abundance: 1.0,
+            min_distinct_minimizers: 100,
+            min_minimizer_fraction: 0.001,
+            min_evenness: 0.5,
+            max_end_enrichment: 3.0,
+        }
+    }
+}
+
+/// A check a taxon of a report failed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub taxid: Option<u64>,
+    pub name: String,
+    pub reads: Option<u64>,
+    pub abundance: f64,
+    /// Name of the failed check
+    pub check: &'static str,
+    /// What was measured, for the user
+    pub detail: String,
+}
+
+/// Evenness of minimizer hits across the read position bins, `None` without hits
+///
+/// The Shannon entropy of the bins divided by its maximum: 1 for hits spread
+/// evenly along the reads, 0 for all hits in one bin.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::report_check::hit_evenness;
+///
+/// assert_eq!(hit_evenness(&[5, 5, 5, 5]), Some(1.0));
+/// assert_eq!(hit_evenness(&[0, 20, 0, 0]), Some(0.0));
+/// assert!(hit_evenness(&[1, 1, 10, 10]).unwrap() < 0.8);
+/// assert_eq!(hit_evenness(&[0, 0]), None);
+/// ```
+pub fn hit_evenness(counts: &[u64]) -> Option<f64> {
+    let total: u64 = counts.iter().sum();
+    if total == 0 {
+        return None;
+    }
+    if counts.len() < 2 {
+        return Some(1.0);
+    }
+    let entropy: f64 = counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / total as f64;
+            -p * p.ln()
+        })
+        .sum();
+    Some(entropy / (counts.len() as f64).ln())
+}
+
+/// Reads a hit heatmap written by resolve in TSV format, keyed by taxid
+pub fn read_heatmap<P: AsRef<Path>>(filename: P) -> Result<HashMap<u64, Vec<u64>>> {
+    let reader = BufReader::new(open_file(filename)?);
+    let mut heatmap = HashMap::new();
+    // taxid, name, total, bin_0 .. bin_n
+    for line in reader.lines().skip(1) {
+        let line = line?;
+        let fields: Vec<&str> = line.trim_end().split('\t').collect();
+        if fields.len() < 4 {
+            continue;
+        }
+        if let Ok(taxid) = fields[0].parse::<u64>() {
+            let counts = fields[3..]
+                .iter()
+                .map(|count| count.parse::<u64>().unwrap_or(0))
+                .collect();
+            heatmap.insert(taxid, counts);
+        }
+    }
+    Ok(heatmap)
+}
+
+/// Flags biologically implausible taxa at `rank` of a report
+///
+/// * `few_distinct_minimizers`: many reads but few distinct minimizers (needs a
+///   report written with `-K`), the reads hit a small, often conserved, part of
+///   the genome rather than the genome as a whole.
+/// * `clustered_hits`: the minimizer hits pile up in a few read position bins.
+/// * `read_end_hits`: hits are enriched at the start and end of the reads, typical
+///   for adapters, primers or barcodes.
+///
+/// The position checks need the heatmap of the sample. Its rows count the hits of
+/// minimizers assigned to exactly that taxon, hits of its strains are not included.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::report_check::{check_entries, CheckThresholds};
+/// use kun_peng::report_import::parse_report;
+/// use std::collections::HashMap;
+///
+/// let kreport = "100.00\t1000\t0\t90000\t5000\t0.5000\tR\t1\troot\n\
+///                60.00\t600\t600\t50000\t4000\t0.8000\tS\t562\t  Escherichia coli\n\
+///                30.00\t300\t300\t30000\t40\t0.0100\tS\t1280\t  Staphylococcus aureus\n\
+///                10.00\t100\t100\t10000\t800\t0.0000\tS\t287\t  Pseudomonas aeruginosa\n";
+/// let entries = parse_report(kreport.as_bytes(), None).unwrap();
+/// let heatmap = HashMap::from([
+///     (562, vec![30, 25, 28, 27, 31, 26, 29, 30]),
+///     (287, vec![90, 2, 1, 3, 2, 1, 4, 80]),
+/// ]);
+///
+/// let findings = check_entries(&entries, "species", Some(&heatmap), &CheckThresholds::default());
+/// let checks: Vec<(u64, &str)> = findings.iter().map(|f| (f.taxid.unwrap(), f.check)).collect();
+/// assert_eq!(
+///     checks,
+///     vec![
+///         (1280, "few_distinct_minimizers"),
+///         (287, "low_minimizer_fraction"),
+///         (287, "read_end_hits"),
+///     ]
+/// );
+/// ```
+pub fn check_entries(
+    entries: &[ReportEntry],
+    rank: &str,
+    heatmap: Option<&HashMap<u64, Vec<u64>>>,
+    thresholds: &CheckThresholds,
+) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    for entry in entries
+        .iter()
+        .filter(|entry| entry.rank == Some(rank) && entry.abundance >= thresholds.min_abundance)
+    {
+        let mut flag = |check: &'static str, detail: String| {
+            findings.push(Finding {
+                taxid: entry.taxid,
+                name: entry.name.clone(),
+                reads: entry.reads,
+                abundance: entry.abundance,
+                check,
+                detail,
+            })
+        };
+
+        if let Some(distinct) = entry.distinct_minimizers {
+            if distinct < thresholds.min_distinct_minimizers {
+                flag(
+                    "few_distinct_minimizers",
+                    format!(
+                        "{} distinct minimizers for {} reads",
+                        distinct,
+                        entry.reads.unwrap_or(0)
+                    ),
+                );
+            }
+        }
+
+        if let Some(fraction) = entry.minimizer_fraction {
+            if fraction < thresholds.min_minimizer_fraction {
+                flag(
+                    "low_minimizer_fraction",
+                    format!(
+                        "minimizer fraction {:.4} for {} reads",
+                        fraction,
+                        entry.reads.unwrap_or(0)
+                    ),
+                );
+            }
+        }
+
+        let counts = match (heatmap, entry.taxid) {
+            (Some(heatmap), Some(taxid)) => heatmap.get(&taxid),
+            _ => None,
+        };
+        let counts = match counts {
+            Some(counts)
+                if !counts.is_empty()
+                    && counts.iter().sum::<u64>() >= MIN_HITS_PER_BIN * counts.len() as u64 =>
+            {
+                counts
+            }
+            _ => continue,
+        };
+        let total: u64 = counts.iter().sum();
+        let bins = counts.len();
+        let end_fraction = (counts[0] + counts[bins - 1]) as f64 / total as f64;
+        let evenness = hit_evenness(counts).unwrap_or(1.0);
+        if bins > 2 && end_fraction * bins as f64 / 2.0 > thresholds.max_end_enrichment {
+            flag(
+                "read_end_hits",
+                format!(
+                    "{:.1}% of {} hits in the first and last of {} position bins",
+                    end_fraction * 100.0,
+                    total,
+                    bins
+                ),
+            );
+        } else if evenness < thresholds.min_evenness {
+            flag(
+                "clustered_hits",
+                format!("hit position evenness {:.2} over {} hits", evenness, total),
+            );
+        }
+    }
+    findings
+}
@@ -0,0 +1,339 @@
+use crate::utils::open_file;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Result};
+use std::path::Path;
+
+/// Report formats that can be imported for comparison
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReportFormat {
+    /// Kraken style report (kreport2), also written by centrifuge-kreport and Bracken
+    Kraken,
+    /// Centrifuge `--report-file` output, read counts are per taxon rather than per clade,
+    /// so it compares best at species rank
+    Centrifuge,
+    /// MetaPhlAn profile
+    Metaphlan,
+}
+
+impl ReportFormat {
+    /// Guesses the format from the first non-empty line of a report
+    pub fn detect(first_line: &str) -> Self {
+        if first_line.starts_with("name\ttaxID\ttaxRank") {
+            ReportFormat::Centrifuge
+        } else if first_line.starts_with('#') && !first_line.starts_with("# ")
+            || first_line.contains("k__")
+        {
+            // MetaPhlAn headers start with `#mpa_` or `#clade_name`, kreport
+            // metadata lines with `# key: value`
+            ReportFormat::Metaphlan
+        } else {
+            ReportFormat::Kraken
+        }
+    }
+}
+
+/// One taxon of an imported report
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReportEntry {
+    /// NCBI taxid, if the report has one
+    pub taxid: Option<u64>,
+    pub name: String,
+    /// Normalized rank name (`domain`, `phylum`, ..., `species`), `None` for other ranks
+    pub rank: Option<&'static str>,
+    /// Number of reads, if the report has read counts
+    pub reads: Option<u64>,
+    /// Abundance in percent as given by the report
+    pub abundance: f64,
+    /// Distinct minimizers of the clade, in Kraken style reports written with `-K`
+    pub distinct_minimizers: Option<u64>,
+    /// Minimizer fraction of the clade, in Kraken style reports written with `--minimizer-fraction`
+    pub minimizer_fraction: Option<f64>,
+}
+
+/// Ranks that can be compared across classifiers, from top to bottom
+pub const RANKS: [&str; 8] = [
+    "domain", "kingdom", "phylum", "class", "order", "family", "genus", "species",
+];
+
+fn normalize_rank(rank: &str) -> Option<&'static str> {
+    match rank.to_ascii_lowercase().as_str() {
+        "d" | "superkingdom" | "domain" => Some("domain"),
+        "k" | "kingdom" => Some("kingdom"),
+        "p" | "phylum" => Some("phylum"),
+        "c" | "class" => Some("class"),
+        "o" | "order" => Some("order"),
+        "f" | "family" => Some("family"),
+        "g" | "genus" => Some("genus"),
+        "s" | "species" => Some("species"),
+        _ => None,
+    }
+}
+
+fn invalid_line(format: ReportFormat, line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid {:?} report line: {}", format, line),
+    )
+}
+
+fn parse_kraken_line(line: &str) -> Option<ReportEntry> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    // pct, clade reads, taxon reads, [minimizers, distinct minimizers,] [minimizer fraction,] rank, taxid, name
+    let (distinct_minimizers, minimizer_fraction) = match fields.len() {
+        6 => (None, None),
+        7 => (None, fields[3].trim().parse().ok()),
+        8 => (fields[4].trim().parse().ok(), None),
+        9 => (fields[4].trim().parse().ok(), fields[5].trim().parse().ok()),
+        _ => return None,
+    };
+    let (rank, taxid, name) = (
+        fields[fields.len() - 3],
+        fields[fields.len() - 2],
+        fields[fields.len() - 1],
+    );
+    Some(ReportEntry {
+        taxid: taxid.trim().parse().ok(),
+        name: name.trim().to_string(),
+        rank: normalize_rank(rank.trim()),
+        reads: fields[1].trim().parse().ok(),
+        abundance: fields[0].trim().parse().ok()?,
+        distinct_minimizers,
+        minimizer_fraction,
+    })
+}
+
+fn parse_centrifuge_line(line: &str) -> Option<ReportEntry> {
+    // name, taxID, taxRank, genomeSize, numReads, numUniqueReads, abundance
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() < 7 {
+        return None;
+    }
+    Some(ReportEntry {
+        taxid: fields[1].trim().parse().ok(),
+        name: fields[0].trim().to_string(),
+        rank: normalize_rank(fields[2].trim()),
+        reads: fields[4].trim().parse().ok(),
+        abundance: fields[6].trim().parse::<f64>().ok()? * 100.0,
+        distinct_minimizers: None,
+        minimizer_fraction: None,
+    })
+}
+
+fn parse_metaphlan_line(line: &str) -> Option<ReportEntry> {
+    // MetaPhlAn 3/4: clade_name, NCBI_tax_id, relative_abundance[, additional_species]
+    // MetaPhlAn 2: clade_name, relative_abundance
+    let fields: Vec<&str> = line.split('\t').collect();
+    let (lineage, taxids, abundance) = match fields.len() {
+        2 => (fields[0], None, fields[1]),
+        n if n >= 3 => (fields[0], Some(fields[1]), fields[2]),
+        _ => return None,
+    };
+    let clade = lineage.rsplit('|').next()?;
+    let (rank, name) = match clade.split_once("__") {
+        // MetaPhlAn calls the top level kingdom, it holds Bacteria, Archaea and Eukaryota
+        Some(("k", name)) => (Some("domain"), name),
+        Some((rank, name)) => (normalize_rank(rank), name),
+        None => (None, clade),
+    };
+    Some(ReportEntry {
+        taxid: taxids.and_then(|ids| ids.rsplit('|').next()?.trim().parse().ok()),
+        name: name.replace('_', " "),
+        rank,
+        reads: None,
+        abundance: abundance.trim().parse().ok()?,
+        distinct_minimizers: None,
+        minimizer_fraction: None,
+    })
+}
+
+/// Parses a report of another classifier (or of kun_peng) into report entries
+///
+/// With `format` set to `None` the format is detected from the first line.
+/// Comment lines and the unclassified line of Kraken style reports are skipped.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::report_import::{parse_report, ReportFormat};
+///
+/// let kreport = "# site: gut\n 10.00\t10\t0\tU\t0\tunclassified\n 90.00\t90\t0\tR\t1\troot\n 90.00\t90\t90\tS\t562\t      Escherichia coli\n";
+/// let entries = parse_report(kreport.as_bytes(), None).unwrap();
+/// assert_eq!(entries.len(), 2);
+/// assert_eq!(entries[1].taxid, Some(562));
+/// assert_eq!(entries[1].rank, Some("species"));
+/// assert_eq!(entries[1].name, "Escherichia coli");
+///
+/// let centrifuge = "name\ttaxID\ttaxRank\tgenomeSize\tnumReads\tnumUniqueReads\tabundance\nEscherichia coli\t562\tspecies\t5000000\t80\t70\t0.8\n";
+/// let entries = parse_report(centrifuge.as_bytes(), None).unwrap();
+/// assert_eq!(entries[0].reads, Some(80));
+/// assert_eq!(entries[0].abundance, 80.0);
+///
+/// let metaphlan = "#mpa_vJan21\n#clade_name\tNCBI_tax_id\trelative_abundance\tadditional_species\nk__Bacteria\t2\t100.0\t\nk__Bacteria|p__Proteobacteria|c__Gammaproteobacteria|o__Enterobacterales|f__Enterobacteriaceae|g__Escherichia|s__Escherichia_coli\t2|1224|1236|91347|543|561|562\t75.5\t\n";
+/// let entries = parse_report(metaphlan.as_bytes(), Some(ReportFormat::Metaphlan)).unwrap();
+/// assert_eq!(entries[0].rank, Some("domain"));
+/// assert_eq!(entries[1].taxid, Some(562));
+/// assert_eq!(entries[1].name, "Escherichia coli");
+/// ```
+pub fn parse_report<R: BufRead>(
+    reader: R,
+    format: Option<ReportFormat>,
+) -> Result<Vec<ReportEntry>> {
+    let mut format = format;
+    let mut entries = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.trim().is_empty() {
+            continue;
+        }
+        let format = *format.get_or_insert_with(|| ReportFormat::detect(line));
+        if line.starts_with('#') || line.starts_with("name\ttaxID") {
+            continue;
+        }
+
+        let entry = match format {
+            ReportFormat::Kraken => parse_kraken_line(line),
+            ReportFormat::Centrifuge => parse_centrifuge_line(line),
+            ReportFormat::Metaphlan => parse_metaphlan_line(line),
+        }
+        .ok_or_else(|| invalid_line(format, line))?;
+
+        if format == ReportFormat::Kraken && entry.taxid == Some(0) {
+            continue;
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Reads a report file, see `parse_report`
+pub fn read_report<P: AsRef<Path>>(
+    filename: P,
+    format: Option<ReportFormat>,
+) -> Result<Vec<ReportEntry>> {
+    parse_report(BufReader::new(open_file(filename)?), format)
+}
+
+/// Relative abundances in percent of the entries at `rank`, keyed by taxid or name
+///
+/// Read counts are used when the report has them, the reported abundance otherwise,
+/// so reports that include unclassified reads or differ in normalization compare on
+/// the same scale.
+pub fn rank_profile(entries: &[ReportEntry], rank: &str) -> HashMap<String, (String, f64)> {
+    let values: Vec<(&ReportEntry, f64)> = entries
+        .iter()
+        .filter(|entry| entry.rank == Some(rank))
+        .map(|entry| {
+            let value = match entry.reads {
+                Some(reads) => reads as f64,
+                None => entry.abundance,
+            };
+            (entry, value)
+        })
+        .collect();
+    let total: f64 = values.iter().map(|(_, value)| value).sum();
+
+    let mut profile: HashMap<String, (String, f64)> = HashMap::new();
+    for (entry, value) in values {
+        let share = if total > 0.0 {
+            100.0 * value / total
+        } else {
+            0.0
+        };
+        profile
+            .entry(profile_key(entry))
+            .or_insert_with(|| (entry.name.clone(), 0.0))
+            .1 += share;
+    }
+    profile
+}
+
+/// Key used to match taxa across reports: the taxid if known, the lowercase name otherwise
+pub fn profile_key(entry: &ReportEntry) -> String {
+    match entry.taxid {
+        Some(taxid) => taxid.to_string(),
+        None => entry.name.to_lowercase(),
+    }
+}
+
+/// Lowercase names of the taxid-keyed taxa of profiles, mapped to their taxid keys
+pub fn taxid_names<'a, I>(profiles: I) -> HashMap<String, String>
+where
+    I: IntoIterator<Item = &'a HashMap<String, (String, f64)>>,
+{
+    let mut taxids = HashMap::new();
+    for profile in profiles {
+        for (key, (name, _)) in profile {
+            if key.parse::<u64>().is_ok() {
+                taxids
+                    .entry(name.to_lowercase())
+                    .or_insert_with(|| key.clone());
+            }
+        }
+    }
+    taxids
+}
+
+/// Re-keys the name-keyed taxa of a profile to the taxid key of the taxon with the
+/// same name, so reports without taxids match reports with them
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::report_import::{match_names, taxid_names};
+/// use std::collections::HashMap;
+///
+/// let kreport: HashMap<String, (String, f64)> =
+///     [("562".to_string(), ("Escherichia coli".to_string(), 100.0))].into();
+/// let mpa: HashMap<String, (String, f64)> = [
+///     ("escherichia coli".to_string(), ("Escherichia coli".to_string(), 80.0)),
+///     ("bacillus subtilis".to_string(), ("Bacillus subtilis".to_string(), 20.0)),
+/// ]
+/// .into();
+/// let mpa = match_names(mpa, &taxid_names([&kreport]));
+/// assert_eq!(mpa["562"].1, 80.0);
+/// assert!(mpa.contains_key("bacillus subtilis"));
+/// ```
+pub fn match_names(
+    profile: HashMap<String, (String, f64)>,
+    taxids: &HashMap<String, String>,
+) -> HashMap<String, (String, f64)> {
+    let mut matched: HashMap<String, (String, f64)> = HashMap::new();
+    for (key, (name, abundance)) in profile {
+        let key = match key.parse::<u64>() {
+            Ok(_) => key,
+            Err(_) => taxids.get(&key).cloned().unwrap_or(key),
+        };
+        matched.entry(key).or_insert_with(|| (name, 0.0)).1 += abundance;
+    }
+    matched
+}
+
+/// Bray-Curtis dissimilarity of two profiles, 0 for identical and 1 for disjoint profiles
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::report_import::bray_curtis;
+///
+/// let a = [("562".to_string(), 60.0), ("1280".to_string(), 40.0)];
+/// let b = [("562".to_string(), 40.0), ("1280".to_string(), 60.0)];
+/// let a = a.into_iter().collect();
+/// let b = b.into_iter().collect();
+/// assert!((bray_curtis(&a, &b) - 0.2).abs() < 1e-9);
+/// assert_eq!(bray_curtis(&a, &a), 0.0);
+/// ```
+pub fn bray_curtis(a: &HashMap<String, f64>, b: &HashMap<String, f64>) -> f64 {
+    let mut shared = 0.0;
+    for (key, value_a) in a {
+        if let Some(value_b) = b.get(key) {
+            shared += value_a.min(*value_b);
+        }
+    }
+    let total: f64 = a.values().sum::<f64>() + b.values().sum::<f64>();
+    if total > 0.0 {
+        1.0 - 2.0 * shared / total
+    } else {
+        0.0
+    }
+}
@@ -1,3 +1,5 @@
+use crate::audit::warn;
+use crate::report::extract_string_from_offset;
 use crate::utils::open_file;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
@@ -42,6 +44,16 @@ pub fn parse_nodes_file<P: AsRef<Path>>(
 
         let fields: Vec<_> = line.split("\t|\t").collect();
         if fields.len() < 3 {
+            warn(
+                "build",
+                "malformed_record",
+                None,
+                &format!(
+                    "skip nodes.dmp line with {} fields: {:?}",
+                    fields.len(),
+                    line
+                ),
+            );
             continue;
         }
 
@@ -96,7 +108,18 @@ pub fn parse_names_file<P: AsRef<Path>>(names_filename: P) -> Result<HashMap<u64
         // Split the line into fields
         let fields: Vec<_> = line.split("\t|\t").collect();
         if fields.len() < 4 {
-            continue; // Skip if the line doesn't have the expected number of fields
+            // Skip if the line doesn't have the expected number of fields
+            warn(
+                "build",
+                "malformed_record",
+                None,
+                &format!(
+                    "skip names.dmp line with {} fields: {:?}",
+                    fields.len(),
+                    line
+                ),
+            );
+            continue;
         }
         // Parse node ID and name type
         let node_id = fields[0].parse::<u64>().unwrap_or(0);
@@ -474,6 +497,61 @@ impl Taxonomy {
             .unwrap_or(&0)
     }
 
+    /// Get the scientific name of a node
+    ///
+    /// # Arguments
+    ///
+    /// * `internal_id` - The internal ID of the node
+    ///
+    /// # Returns
+    ///
+    /// The name of the node, or an empty string if the node does not exist
+    pub fn name_of(&self, internal_id: u32) -> &str {
+        match self.nodes.get(internal_id as usize) {
+            Some(node) => extract_string_from_offset(&self.name_data, node.name_offset as usize),
+            None => "",
+        }
+    }
+
+    /// Get the rank of a node, or an empty string if the node does not exist
+    pub fn rank_of(&self, internal_id: u32) -> &str {
+        match self.nodes.get(internal_id as usize) {
+            Some(node) => extract_string_from_offset(&self.rank_data, node.rank_offset as usize),
+            None => "",
+        }
+    }
+
+    /// Get the names of a node and all of its ancestors, from root down to the node
+    pub fn lineage_names(&self, internal_id: u32) -> Vec<&str> {
+        self.path_cache
+            .get(&internal_id)
+            .map(|path| path.iter().map(|&id| self.name_of(id)).collect())
+            .unwrap_or_default()
+    }
+
+    /// Check whether another taxonomy uses the same taxids as this one
+    ///
+    /// Two databases built from the same taxonomy dump only keep the nodes their
+    /// libraries need, so their node lists differ. They still share taxids when every
+    /// taxid found in both has the same name and the same parent in both.
+    pub fn shares_taxids(&self, other: &Taxonomy) -> bool {
+        let mut shared = 0usize;
+        for (id, node) in other.nodes.iter().enumerate().skip(1) {
+            let own_id = self.get_internal_id(node.external_id);
+            if own_id == 0 {
+                continue;
+            }
+            let own_node = &self.nodes[own_id as usize];
+            let own_parent = self.nodes[own_node.parent_id as usize].external_id;
+            let parent = other.nodes[node.parent_id as usize].external_id;
+            if own_parent != parent || self.name_of(own_id) != other.name_of(id as u32) {
+                return false;
+            }
+            shared += 1;
+        }
+        shared > 0
+    }
+
     /// Generate the mapping from external to internal IDs
     pub fn generate_external_to_internal_id_map(&mut self) {
         self.external_to_internal_id_map.clear();
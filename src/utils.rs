@@ -1,7 +1,9 @@
+use crate::audit::warn;
 use std::collections::{BTreeMap as Map, HashMap};
 use std::fs::{self, create_dir_all, File, OpenOptions};
-use std::io::{self, BufRead, BufReader, BufWriter, Result};
+use std::io::{self, BufRead, BufReader, BufWriter, Result, Write};
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use walkdir::WalkDir;
 
 /// Reads the seqid2taxid.map file to create a mapping for trimming the NCBI taxonomy tree.
@@ -18,19 +20,33 @@ use walkdir::WalkDir;
 /// Returns a `Result` containing a `HashMap<String, u64>` where the keys are sequence IDs
 /// and the values are taxon IDs, or an error if the file cannot be read or parsed.
 pub fn read_id_to_taxon_map<P: AsRef<Path>>(filename: P) -> Result<HashMap<String, u64>> {
+    let filename = filename.as_ref();
     let file = open_file(filename)?;
     let reader = BufReader::new(file);
     let mut id_map = HashMap::new();
 
-    for line in reader.lines() {
+    for (number, line) in reader.lines().enumerate() {
         let line = line?;
-        let parts: Vec<&str> = line.trim().split_whitespace().collect();
-        if parts.len() < 2 {
+        if line.trim().is_empty() {
             continue;
         }
-        let seq_id = parts[0].to_string();
-        if let Ok(taxid) = parts[1].parse::<u64>() {
-            id_map.insert(seq_id, taxid);
+        let parts: Vec<&str> = line.trim().split_whitespace().collect();
+        let taxid = parts.get(1).and_then(|taxid| taxid.parse::<u64>().ok());
+        match taxid {
+            Some(taxid) => {
+                id_map.insert(parts[0].to_string(), taxid);
+            }
+            None => warn(
+                "build",
+                "malformed_record",
+                None,
+                &format!(
+                    "skip line {} of {:?}, expected a sequence id and a taxid: {:?}",
+                    number + 1,
+                    filename,
+                    line
+                ),
+            ),
         }
     }
 
@@ -178,6 +194,52 @@ pub fn set_fd_limit(_new_limit: u64) -> io::Result<()> {
     Ok(())
 }
 
+/// Get the disk space available to unprivileged users on the file system of `path`.
+///
+/// If `path` does not exist yet, its closest existing ancestor is used.
+///
+/// # Returns
+///
+/// The available space in bytes, or `None` if it couldn't be determined.
+#[cfg(unix)]
+pub fn available_space<P: AsRef<Path>>(path: P) -> Option<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = path.as_ref();
+    let existing = path.ancestors().find(|p| p.exists()).unwrap_or(path);
+    let existing = if existing.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        existing
+    };
+    let c_path = CString::new(existing.as_os_str().as_bytes()).ok()?;
+
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) };
+    if result == 0 {
+        Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+    } else {
+        None
+    }
+}
+
+#[cfg(windows)]
+pub fn available_space<P: AsRef<Path>>(_path: P) -> Option<u64> {
+    None
+}
+
+/// What to do when the estimated size of the intermediate files exceeds the free disk space
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum DiskCheck {
+    /// Stop before writing any chunk file
+    Error,
+    /// Print a warning and continue
+    Warn,
+    /// Skip the check
+    Off,
+}
+
 pub fn create_partition_files(partition: usize, base_path: &PathBuf, prefix: &str) -> Vec<PathBuf> {
     create_dir_all(&base_path).expect(&format!("create dir error {:?}", base_path));
     let file_path = base_path.clone();
@@ -403,3 +465,103 @@ pub fn get_lastest_file_index(file_path: &PathBuf) -> Result<usize> {
     };
     Ok(index)
 }
+
+/// Reads `sample_file.map` into a map from file index to the input paths of that sample
+pub fn read_sample_file_map<P: AsRef<Path>>(file_path: P) -> Result<Map<usize, Vec<String>>> {
+    let file_content = fs::read_to_string(file_path)?;
+    let mut sample_files = Map::new();
+    for line in file_content.lines() {
+        if let Some((index, paths)) = line.split_once('\t') {
+            if let Ok(index) = index.parse::<usize>() {
+                let paths = paths.split(',').map(|p| p.to_string()).collect();
+                sample_files.insert(index, paths);
+            }
+        }
+    }
+    Ok(sample_files)
+}
+
+/// A buffered writer that also flushes once `flush_interval` has elapsed since the last flush
+///
+/// The writer has no timer of its own: the interval is checked on every write and
+/// on [`IntervalWriter::flush_if_due`]. Data written just before a writer goes idle
+/// stays buffered until its next write, a `flush_if_due` call, an explicit flush or
+/// drop, so callers holding idle writers should call `flush_if_due` between batches.
+///
+/// # Examples
+///
+/// ```
+/// use kun_peng::utils::IntervalWriter;
+/// use std::io::Write;
+/// use std::time::Duration;
+///
+/// let mut writer = IntervalWriter::with_capacity(1024, Some(Duration::ZERO), Vec::new());
+/// writer.write_all(b"C\tread_1\t9606\n").unwrap();
+/// assert_eq!(writer.get_ref().len(), 14);
+/// ```
+pub struct IntervalWriter<W: Write> {
+    inner: BufWriter<W>,
+    flush_interval: Option<Duration>,
+    last_flush: Instant,
+}
+
+impl<W: Write> IntervalWriter<W> {
+    pub fn with_capacity(capacity: usize, flush_interval: Option<Duration>, inner: W) -> Self {
+        Self {
+            inner: BufWriter::with_capacity(capacity, inner),
+            flush_interval,
+            last_flush: Instant::now(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.inner.get_ref()
+    }
+
+    /// Flushes if `flush_interval` has elapsed since the last flush
+    pub fn flush_if_due(&mut self) -> io::Result<()> {
+        match self.flush_interval {
+            Some(interval) if self.last_flush.elapsed() >= interval => self.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Write for IntervalWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.flush_if_due()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.last_flush = Instant::now();
+        self.inner.flush()
+    }
+}
+
+/// Buffer capacity and flush interval of the per-sample output writers
+#[derive(Clone, Copy, Debug)]
+pub struct WriterConfig {
+    pub capacity: usize,
+    pub flush_interval: Option<Duration>,
+}
+
+impl WriterConfig {
+    /// Creates a config, a `flush_interval_secs` of 0 disables the periodic flush
+    pub fn new(capacity: usize, flush_interval_secs: u64) -> Self {
+        let flush_interval = if flush_interval_secs > 0 {
+            Some(Duration::from_secs(flush_interval_secs))
+        } else {
+            None
+        };
+        Self {
+            capacity,
+            flush_interval,
+        }
+    }
+
+    pub fn writer<W: Write>(&self, inner: W) -> IntervalWriter<W> {
+        IntervalWriter::with_capacity(self.capacity, self.flush_interval, inner)
+    }
+}